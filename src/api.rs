@@ -1,103 +1,1586 @@
-pub use std::net::Ipv4Addr;
+pub use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::collections::HashMap;
+use std::error::Error as _;
+use std::fmt;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::audit::{self, AuditEntry, AuditLog};
+use crate::breaker::CircuitBreaker;
+use crate::cache::RecordCache;
+use crate::history::{self, History, HistoryEntry};
 use crate::error;
+use crate::ip;
+use crate::middleware::{self, Middleware};
+use crate::protocol::{self, record_type};
+use crate::resolver::Resolver;
+use crate::state::State;
+use crate::validate;
+use crate::watch::Watch;
 
 // Tokens generated by AnonDNS are random 32-character string hashes
-type Token = String;
-
-#[derive(serde_derive::Deserialize, Debug)]
-struct Response {
-    code: i32,
-    data: String,
-    #[serde(default, rename = "name")]
-    _name: Option<String>,
-    #[serde(default, rename = "status")]
-    _status: Option<i32>,
-    #[serde(default, rename = "type")]
-    _ftype: Option<String>,
-    #[serde(default)]
-    token: Option<String>,
-    #[serde(default, rename = "updated")]
-    _updated: Option<String>
+pub type Token = String;
+
+/// The base URL of the public AnonDNS API
+pub const DEFAULT_ENDPOINT: &str = "https://anondns.net";
+
+/// The parent zone records are created under unless configured otherwise
+pub const DEFAULT_ZONE: &str = "anondns.net";
+
+/// The largest response body a service accepts unless configured otherwise, in bytes
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// The token returned by registrations on a service built with `ServiceBuilder::dry_run`
+pub const DRY_RUN_TOKEN: &str = "00000000000000000000000000000000";
+
+/// The version of the AnonDNS API a `Service` speaks
+///
+/// The API does not report its own version, so it is declared on the `ServiceBuilder` and decides how
+/// each request URL is laid out. New variants will be added alongside `V1` as the API changes, so
+/// existing users keep talking to the version they were written against.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ApiVersion {
+    /// The `/api/register` and `/api/set` endpoints
+    #[default]
+    V1
+}
+
+/// A record this crate has registered or updated, as remembered by the service's `RecordCache`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManagedRecord {
+    pub name: String,
+    /// The record type, `"a"` or `"aaaa"`
+    pub record_type: &'static str,
+    /// The last target the API confirmed
+    pub target: IpAddr,
+    /// When the API last confirmed the target
+    pub updated_at: SystemTime
+}
+
+impl ManagedRecord {
+    /// Estimates how long until the record expires, if the provider expires records inactive for `cutoff`
+    ///
+    /// Returns `Duration::ZERO` once the cutoff has passed. AnonDNS does not document a cutoff, so pass
+    /// the one observed or the most conservative one acceptable.
+    pub fn expires_in(&self, cutoff: Duration) -> Duration {
+        let age = self.updated_at.elapsed().unwrap_or_default();
+        cutoff.saturating_sub(age)
+    }
+}
+
+/// An API response exactly as it arrived, returned by `Service::register_raw` and `Service::update_raw`
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    /// The HTTP status of the response
+    pub status: reqwest::StatusCode,
+    pub headers: HeaderMap,
+    /// The undecoded response body
+    pub body: Vec<u8>,
+    /// The identifier of the request
+    pub request_id: RequestId
+}
+
+impl Envelope {
+    /// Decodes the body, giving access to the raw `code` and `data` and any fields this crate does not model
+    pub fn parse(&self) -> Result<protocol::Response, error::DnsApiError> {
+        protocol::parse(&self.body)
+    }
+}
+
+/// Identifies one API call in log output, outcomes and, if configured, a request header
+///
+/// Identifiers are unique within a process and very likely unique across processes on one host.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestId(String);
+
+impl RequestId {
+    fn generate() -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        RequestId(format!("{:x}-{:x}-{:x}", millis, std::process::id(), count))
+    }
+
+    /// Returns the identifier as a string slice
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The details the API reports about a record alongside the result of an operation
+///
+/// Fields are `None` when the server left them out of its response, or when no request was sent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Record {
+    /// The fully qualified name of the record
+    pub name: Option<String>,
+    /// The type of the record, such as `"a"`
+    pub record_type: Option<String>,
+    pub status: Option<i32>,
+    /// When the record was last updated, as reported by the server
+    pub updated: Option<String>,
+    /// Fields of the response this crate does not model yet, as the server sent them
+    pub extra: HashMap<String, serde_json::Value>
+}
+
+impl From<&protocol::Response> for Record {
+    fn from(response: &protocol::Response) -> Self {
+        Record {
+            name: response.name.clone(),
+            record_type: response.record_type.clone(),
+            status: response.status,
+            updated: response.updated.clone(),
+            extra: response.unknown.clone()
+        }
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl Record {
+    /// Parses the server-reported `updated` timestamp, if it is present and in a recognised format
+    pub fn updated_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.updated.as_deref().and_then(parse_timestamp)
+    }
+}
+
+/// How long a request took
+///
+/// reqwest does not report the DNS lookup and connection separately, so they are part of `response`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    /// From sending the attempt that succeeded until its response headers arrived
+    pub response: Duration,
+    /// From sending the first attempt until the body was read, including any retries and their delays
+    pub total: Duration,
+    /// How many attempts were sent, 1 unless the request was retried
+    pub attempts: u32
+}
+
+/// Everything the API reports about a newly registered subdomain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisterOutcome {
+    /// The token needed to update the subdomain later on
+    pub token: Token,
+    /// The record that was created
+    pub record: Record,
+    /// `true` if the outcome came from a sandbox service rather than the production API
+    pub sandbox: bool,
+    /// `true` if the registration was only simulated, see `ServiceBuilder::dry_run`
+    pub dry_run: bool,
+    /// The identifier of the request that registered the subdomain
+    pub request_id: RequestId,
+    /// How long the request took, or `None` if the registration was only simulated
+    pub timing: Option<Timing>
+}
+
+/// The result of updating a subdomain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateOutcome {
+    /// The target the API confirmed for the subdomain
+    pub target: IpAddr,
+    /// The target the updated record, A or AAAA, pointed to before the update
+    ///
+    /// The API does not report this itself, so it is taken from the `RecordCache` attached to the
+    /// service and is `None` when there is no cache or it did not hold the record.
+    pub previous: Option<IpAddr>,
+    /// The record as reported by the server, empty if the cache made the request unnecessary
+    pub record: Record,
+    /// `true` if the outcome came from a sandbox service rather than the production API
+    pub sandbox: bool,
+    /// `true` if the update was only simulated, see `ServiceBuilder::dry_run`
+    pub dry_run: bool,
+    /// The identifier of the request that updated the subdomain, or `None` if the cache made it unnecessary
+    pub request_id: Option<RequestId>,
+    /// How long the request took, or `None` if none was sent
+    pub timing: Option<Timing>
+}
+
+// Accepts RFC 3339, a plain "YYYY-MM-DD HH:MM:SS" taken to be UTC, and Unix timestamps in seconds
+#[cfg(feature = "chrono")]
+pub(crate) fn parse_timestamp(value: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    use chrono::{DateTime, NaiveDateTime, Utc};
+
+    let value = value.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Some(time.with_timezone(&Utc));
+    }
+    if let Ok(time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f") {
+        return Some(time.and_utc());
+    }
+
+    value.parse().ok().and_then(|secs| DateTime::from_timestamp(secs, 0))
+}
+
+/// The IP versions used to connect to the API
+///
+/// With `Any`, connections try both families and fall back quickly from one that is unroutable, so
+/// IPv6-only hosts work without configuration. Forcing a family also decides which kind of public
+/// address the API sees.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    #[default]
+    Any,
+    V4,
+    V6
+}
+
+/// How often a call is retried after the API could not be reached
+///
+/// A call is retried when every endpoint failed to connect, timed out or answered with a 5xx or 429
+/// status. The delay doubles after each attempt. A registration whose response was lost may already
+/// have succeeded, in which case its retry is rejected because the name is taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+    retries: u32,
+    delay: Duration
+}
+
+impl RetryPolicy {
+    /// Never retries, the default
+    pub fn none() -> Self {
+        RetryPolicy::default()
+    }
+
+    /// Retries up to `retries` times, waiting `delay` before the first retry
+    pub fn new(retries: u32, delay: Duration) -> Self {
+        RetryPolicy { retries, delay }
+    }
+
+    // The delay before retry number `attempt`, counting from 0
+    fn delay(&self, attempt: u32) -> Duration {
+        self.delay.saturating_mul(1 << attempt.min(16))
+    }
+}
+
+/// Settings that apply to the operations of one service handle, see `Service::with_options`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CallOptions {
+    timeout: Option<Duration>,
+    retry: Option<RetryPolicy>
 }
 
+impl CallOptions {
+    /// Creates options that change nothing
+    pub fn new() -> Self {
+        CallOptions::default()
+    }
+
+    /// Sets the timeout of each request, overriding the client's timeout
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the retry policy, overriding the one the service was built with
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+}
+
+/// A client for the AnonDNS API
+///
+/// All operations take `&self` and the service is `Send + Sync`, so one instance can be shared between
+/// threads behind an `Arc`. Clones are cheap and share the connection pool, circuit breaker and record
+/// cache of the service they were cloned from.
+#[derive(Debug, Clone)]
 pub struct Service {
-    client: reqwest::blocking::Client,
+    client: Arc<OnceLock<reqwest::blocking::Client>>,
+    client_config: ClientConfig,
+    options: CallOptions,
+    version: ApiVersion,
+    endpoints: Vec<String>,
+    zone: String,
+    headers: HeaderMap,
+    request_id_header: Option<HeaderName>,
+    middleware: middleware::Stack,
+    sandbox: bool,
+    allow_private: bool,
+    reserved: Vec<String>,
+    strict: bool,
+    dry_run: bool,
+    retry: RetryPolicy,
+    max_response_size: usize,
+    breaker: Option<Arc<Mutex<CircuitBreaker>>>,
+    cache: Option<Arc<Mutex<RecordCache>>>,
+    history: Option<History>,
+    audit: Option<AuditLog>
 }
 
-impl Service {
-    /// Creates a new instance of the API service and initializes a reqwest blocking client
+/// Configures and builds a `Service`
+#[derive(Debug, Clone)]
+pub struct ServiceBuilder {
+    client: Option<reqwest::blocking::Client>,
+    client_config: ClientConfig,
+    version: ApiVersion,
+    endpoints: Vec<String>,
+    zone: String,
+    headers: HeaderMap,
+    request_id_header: Option<HeaderName>,
+    middleware: middleware::Stack,
+    sandbox: bool,
+    allow_private: bool,
+    reserved: Vec<String>,
+    strict: bool,
+    dry_run: bool,
+    retry: RetryPolicy,
+    max_response_size: usize,
+    breaker: Option<CircuitBreaker>,
+    cache: Option<RecordCache>,
+    history: Option<History>,
+    audit: Option<AuditLog>
+}
+
+impl Default for ServiceBuilder {
+    fn default() -> Self {
+        ServiceBuilder {
+            client: None,
+            client_config: ClientConfig::default(),
+            version: ApiVersion::default(),
+            endpoints: vec![String::from(DEFAULT_ENDPOINT)],
+            zone: String::from(DEFAULT_ZONE),
+            headers: HeaderMap::new(),
+            request_id_header: None,
+            middleware: middleware::Stack::default(),
+            sandbox: false,
+            allow_private: false,
+            reserved: Vec::new(),
+            strict: false,
+            dry_run: false,
+            retry: RetryPolicy::none(),
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            breaker: None,
+            cache: None,
+            history: None,
+            audit: None
+        }
+    }
+}
+
+impl ServiceBuilder {
+    /// Creates a builder with the same defaults as `Service::new()`
     pub fn new() -> Self {
-        Service {
-            client: reqwest::blocking::Client::new()
+        ServiceBuilder::default()
+    }
+
+    /// Sets the timeout applied to each request, which defaults to reqwest's 30 seconds
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.client_config.timeout = Some(timeout);
+        self
+    }
+
+    /// Binds outgoing API connections to a local address
+    ///
+    /// On hosts with several uplinks this selects the one the update leaves through, and with it the
+    /// public address the server sees. Binding to a named interface is not supported by reqwest's
+    /// blocking client, so use the address assigned to that interface.
+    pub fn local_address(mut self, address: impl Into<IpAddr>) -> Self {
+        self.client_config.local_address = Some(address.into());
+        self
+    }
+
+    /// Restricts API connections to one IP version, `AddressFamily::Any` unless set
+    ///
+    /// A `local_address` already fixes the family, in which case this is ignored.
+    pub fn address_family(mut self, family: AddressFamily) -> Self {
+        self.client_config.family = family;
+        self
+    }
+
+    /// Keeps at most `max` idle connections per host, reqwest keeps any number unless set
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.client_config.pool_max_idle = Some(max);
+        self
+    }
+
+    /// Closes connections left idle for longer than `timeout`, which defaults to reqwest's 90 seconds
+    ///
+    /// Daemons updating every few minutes should set this above their interval, so each cycle reuses
+    /// the previous cycle's TLS connection instead of paying for a new handshake. Pass `None` to keep
+    /// idle connections open indefinitely.
+    pub fn pool_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.client_config.pool_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sends TCP keep-alive probes on idle connections every `interval`, so NAT devices and firewalls
+    /// do not silently drop pooled connections
+    pub fn tcp_keepalive(mut self, interval: Duration) -> Self {
+        self.client_config.tcp_keepalive = Some(interval);
+        self
+    }
+
+    /// Follows at most `max` redirects per request, 10 unless set; 0 disables following them
+    ///
+    /// Redirects are only followed to the hosts of the configured endpoints and to anondns.net. A
+    /// redirect anywhere else, typically by a captive portal, fails the call with
+    /// `DnsApiError::UnexpectedResponse` instead of sending the token-bearing path to that host.
+    pub fn redirects(mut self, max: usize) -> Self {
+        self.client_config.max_redirects = Some(max);
+        self
+    }
+
+    /// Resolves `host` to the given addresses instead of asking a resolver
+    ///
+    /// This keeps the API reachable where the resolver is broken or censored. The port of each address
+    /// is ignored in favour of the endpoint's. With the `hickory` feature, other hosts are resolved by
+    /// hickory-resolver using the system's upstream servers rather than by the system resolver itself.
+    pub fn resolve(mut self, host: &str, addresses: &[SocketAddr]) -> Self {
+        self.client_config.overrides.push((host.to_owned(), addresses.to_vec()));
+        self
+    }
+
+    /// Trusts only the given certificate, and any others pinned, instead of the system's root certificates
+    ///
+    /// Pin the certificate of the API server itself or of the authority that issued it, so a network that
+    /// controls some other trusted authority cannot intercept the token-bearing requests. Remember to pin
+    /// the replacement before the server's certificate is rotated. The TLS backend cannot pin public key
+    /// hashes, so pins are whole certificates.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let pem = std::fs::read("anondns-ca.pem").unwrap();
+    /// let service = anondns_api::api::Service::builder()
+    ///     .pin_certificate(reqwest::Certificate::from_pem(&pem).unwrap())
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn pin_certificate(mut self, certificate: reqwest::Certificate) -> Self {
+        self.client_config.pinned.push(certificate);
+        self
+    }
+
+    /// Accepts any TLS certificate, including self-signed and expired ones, **disabling protection against
+    /// interception entirely**
+    ///
+    /// This is meant for testing against a local HTTPS mock server. Building fails if any endpoint is
+    /// the production API.
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.client_config.accept_invalid_certs = accept;
+        self
+    }
+
+    /// Uses an already configured reqwest blocking client instead of building one
+    ///
+    /// The client is used as is, so other HTTP settings of this builder, such as `timeout`, `local_address`,
+    /// `address_family`, `resolve`, `pin_certificate` and `danger_accept_invalid_certs`, are ignored.
+    pub fn client(mut self, client: reqwest::blocking::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Declares the API version to speak, `ApiVersion::V1` unless set
+    pub fn api_version(mut self, version: ApiVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Sets the parent zone records live under, `DEFAULT_ZONE` unless set
+    ///
+    /// Use this for a self-hosted instance or an additional zone of the service. Subdomains are always
+    /// given without the zone, which this service appends to request paths and lookups.
+    pub fn zone(mut self, zone: &str) -> Self {
+        self.zone = zone.trim_matches('.').to_owned();
+        self
+    }
+
+    /// Replaces the primary API base URL, `DEFAULT_ENDPOINT` unless set
+    pub fn endpoint(mut self, url: &str) -> Self {
+        self.endpoints[0] = url.trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// Adds a mirror base URL that is tried, in the order added, when the endpoints before it fail
+    ///
+    /// A request fails over to the next endpoint when it cannot connect, times out or gets a server error
+    /// (5xx) response. Requests the API answers, including rejections, are never retried elsewhere.
+    pub fn mirror(mut self, url: &str) -> Self {
+        self.endpoints.push(url.trim_end_matches('/').to_owned());
+        self
+    }
+
+    /// Adds a header sent with every API request, such as the credentials an egress proxy requires
+    ///
+    /// Headers are added to each request rather than to the client, so they also apply to a client set
+    /// with `client`. Adding a header with a name already added sends both values.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Sends the identifier of each request in a header with the given name, such as `x-request-id`
+    pub fn request_id_header(mut self, name: HeaderName) -> Self {
+        self.request_id_header = Some(name);
+        self
+    }
+
+    /// Adds middleware that sees every request and response, running after the middleware added before it
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Points the service at a test server instead of the production API and marks every outcome as sandboxed
+    ///
+    /// `url` replaces all configured endpoints. Building fails if any endpoint, including mirrors added
+    /// afterwards, points at the production API, so CI pipelines cannot change real records by mistake.
+    pub fn sandbox(mut self, url: &str) -> Self {
+        self.endpoints = vec![url.trim_end_matches('/').to_owned()];
+        self.sandbox = true;
+        self
+    }
+
+    /// Accepts loopback, link-local and private targets, which are rejected unless set
+    ///
+    /// Enable this for split-horizon setups where a record deliberately points into a private network.
+    /// See `validate::target` for the checks applied.
+    pub fn allow_private_targets(mut self, allow: bool) -> Self {
+        self.allow_private = allow;
+        self
+    }
+
+    /// Refuses to register any of the given names, adding to the names already reserved
+    ///
+    /// Nothing is reserved unless set; `validate::COMMON_RESERVED_NAMES` holds a typical list. Only
+    /// registering is checked, so records that already exist can still be updated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use anondns_api::validate::COMMON_RESERVED_NAMES;
+    ///
+    /// let service = anondns_api::api::Service::builder()
+    ///     .reserved_names(COMMON_RESERVED_NAMES.iter().copied())
+    ///     .reserved_names(["staging"])
+    ///     .build()
+    ///     .unwrap();
+    /// assert!(service.register("www", std::net::Ipv4Addr::new(203, 0, 113, 1)).is_err());
+    /// ```
+    pub fn reserved_names<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>
+    {
+        self.reserved.extend(names.into_iter().map(Into::into));
+        self
+    }
+
+    /// Fails with `DnsApiError::UnexpectedResponse` when a response does not look exactly as expected
+    ///
+    /// Strict mode rejects responses with unknown fields, a missing code or one that is not a JSON
+    /// integer, malformed tokens, or an update confirmed for a different target than requested, instead
+    /// of ignoring them. Use it in CI to notice API changes early.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Validates and logs operations without sending them, returning simulated successes
+    ///
+    /// Registrations return `DRY_RUN_TOKEN` and updates confirm the requested target. Nothing is written
+    /// to the cache, history or audit log, so the service's view of the records stays unchanged.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Fails calls whose response body is larger than `bytes`, `DEFAULT_MAX_RESPONSE_SIZE` unless set
+    ///
+    /// API responses are tiny, so a large body is a captive portal or middlebox page that is not worth
+    /// buffering. Oversized responses fail with `DnsApiError::UnexpectedResponse`.
+    pub fn max_response_size(mut self, bytes: usize) -> Self {
+        self.max_response_size = bytes;
+        self
+    }
+
+    /// Sets the retry policy of every call, which `CallOptions::retry` can override per handle
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Enables a circuit breaker that rejects calls for `cooldown` after `threshold` consecutive failures
+    pub fn circuit_breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+        self.breaker = Some(CircuitBreaker::new(threshold, cooldown));
+        self
+    }
+
+    /// Enables a circuit breaker whose cool-down doubles after each failed trial call, up to `max_cooldown`
+    pub fn circuit_breaker_with_backoff(mut self, threshold: u32, cooldown: Duration, max_cooldown: Duration) -> Self {
+        self.breaker = Some(CircuitBreaker::new(threshold, cooldown).backoff(max_cooldown));
+        self
+    }
+
+    /// Attaches a record-state cache, which is updated on every success and used to skip no-op updates
+    pub fn cache(mut self, cache: RecordCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Attaches a local history, to which every successful register and update is appended
+    pub fn history(mut self, history: History) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// Attaches an audit log, to which every register and update attempt is appended, including failures
+    pub fn audit_log(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Builds the `Service`
+    ///
+    /// The reqwest blocking client, and with it the TLS backend, is only initialized when the service
+    /// sends its first request, so building a service that is never used costs next to nothing.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DnsApiError::InvalidConfig(String)` if a sandbox service, or one accepting invalid
+    /// certificates, would talk to the production API.
+    pub fn build(self) -> Result<Service, error::DnsApiError> {
+        if self.sandbox && self.endpoints.iter().any(|endpoint| is_production(endpoint)) {
+            return Err(error::DnsApiError::InvalidConfig(String::from("sandbox service cannot use the production API endpoint")));
+        }
+        if self.client_config.accept_invalid_certs && self.endpoints.iter().any(|endpoint| is_production(endpoint)) {
+            return Err(error::DnsApiError::InvalidConfig(String::from("invalid certificates cannot be accepted from the production API")));
         }
+
+        let mut client_config = self.client_config;
+        client_config.endpoint_hosts = self.endpoints.iter()
+            .filter_map(|endpoint| reqwest::Url::parse(endpoint).ok()?.host_str().map(str::to_owned))
+            .collect();
+
+        let client = OnceLock::new();
+        if let Some(configured) = self.client {
+            let _ = client.set(configured);
+        }
+
+        Ok(Service {
+            client: Arc::new(client),
+            client_config,
+            options: CallOptions::default(),
+            version: self.version,
+            endpoints: self.endpoints,
+            zone: self.zone,
+            headers: self.headers,
+            request_id_header: self.request_id_header,
+            middleware: self.middleware,
+            sandbox: self.sandbox,
+            allow_private: self.allow_private,
+            reserved: self.reserved,
+            strict: self.strict,
+            dry_run: self.dry_run,
+            retry: self.retry,
+            max_response_size: self.max_response_size,
+            breaker: self.breaker.map(|breaker| Arc::new(Mutex::new(breaker))),
+            cache: self.cache.map(|cache| Arc::new(Mutex::new(cache))),
+            history: self.history,
+            audit: self.audit
+        })
+    }
+}
+
+impl Default for Service {
+    fn default() -> Self {
+        Service::new()
+    }
+}
+
+impl Service {
+    /// Creates a new instance of the API service using the public API
+    pub fn new() -> Self {
+        ServiceBuilder::new().build().expect("default service configuration is valid")
+    }
+
+    /// Creates a new instance of the API service whose requests time out after `timeout`
+    pub fn with_timeout(timeout: Duration) -> Self {
+        ServiceBuilder::new().timeout(timeout).build().expect("default service configuration is valid")
+    }
+
+    /// Creates a new instance of the API service that sends its requests through an existing reqwest client
+    pub fn with_client(client: reqwest::blocking::Client) -> Self {
+        ServiceBuilder::new().client(client).build().expect("default service configuration is valid")
+    }
+
+    /// Creates a sandbox service that talks to the test server at `url`, see `ServiceBuilder::sandbox`
+    pub fn sandbox(url: &str) -> Result<Self, error::DnsApiError> {
+        ServiceBuilder::new().sandbox(url).build()
+    }
+
+    /// Returns `true` if this service talks to a test server rather than the production API
+    pub fn is_sandbox(&self) -> bool {
+        self.sandbox
+    }
+
+    /// Returns a handle to this service whose operations use the given options
+    ///
+    /// The handle is a clone, so it shares the connection pool, breaker and cache with this service.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use anondns_api::api::{CallOptions, Service};
+    ///
+    /// let service = Service::new();
+    /// let quick = service.with_options(CallOptions::new().timeout(Duration::from_secs(2)));
+    /// quick.update("example_subdomain", std::net::Ipv4Addr::new(203, 0, 113, 1), "example_token").unwrap();
+    /// ```
+    pub fn with_options(&self, options: CallOptions) -> Service {
+        Service { options, ..self.clone() }
+    }
+
+    /// Returns a `ServiceBuilder` for configuring a new service
+    pub fn builder() -> ServiceBuilder {
+        ServiceBuilder::new()
+    }
+
+    /// Returns a snapshot of the circuit breaker guarding this service, if one was configured
+    pub fn circuit_breaker(&self) -> Option<CircuitBreaker> {
+        self.breaker.as_ref().map(|breaker| lock(breaker).clone())
+    }
+
+    /// Returns a snapshot of the record-state cache attached to this service, if one was configured
+    pub fn cache(&self) -> Option<RecordCache> {
+        self.cache.as_ref().map(|cache| lock(cache).clone())
+    }
+
+    /// Returns the records this service has registered or updated, sorted by name, without calling the API
+    ///
+    /// Records are taken from the attached `RecordCache`, so the iterator is empty without one.
+    pub fn records(&self) -> impl Iterator<Item = ManagedRecord> {
+        let records: Vec<ManagedRecord> = self.cache.as_ref()
+            .map(|cache| {
+                lock(cache).iter()
+                    .map(|(name, record)| ManagedRecord {
+                        name: name.to_owned(),
+                        record_type: record_type(&record.target),
+                        target: record.target,
+                        updated_at: record.confirmed_at
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        records.into_iter()
+    }
+
+    /// Returns the managed records that expire within `warn_within`, given the provider's inactivity `cutoff`
+    ///
+    /// A warning is logged for each one, so calling this periodically is enough to be told about records
+    /// at risk. Renew them with `renew` or `renew_due`.
+    pub fn expiring_records(&self, cutoff: Duration, warn_within: Duration) -> Vec<ManagedRecord> {
+        self.records()
+            .filter(|record| record.expires_in(cutoff) <= warn_within)
+            .inspect(|record| log::warn!("{} expires in about {}s unless it is renewed", record.name, record.expires_in(cutoff).as_secs()))
+            .collect()
+    }
+
+    /// Returns the local history attached to this service, if one was configured
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
     }
  
-    /// Registers a new DNS subdomain and returns its associated `Token` upon success
+    /// Registers a new DNS subdomain and returns its associated `Token` and record details upon success
     ///
     /// # Arguments
     ///
-    /// * `subdomain` - A string slice containing the name that will be used for the subdomain
-    /// * `target` - The Ipv4Addr the subdomain will redirect to 
+    /// * `subdomain` - A string slice containing the name that will be used for the subdomain, or a wildcard such as `*.name`
+    /// * `target` - The address the subdomain will redirect to, creating an A record for IPv4 and AAAA for IPv6
     ///
     /// # Errors
     /// 
     /// This function may return one of the following error variants:
+    /// - `DnsApiError::InvalidSubdomain(String)`
+    /// - `DnsApiError::InvalidTarget(String)`
     /// - `DnsApiError::BadRequest((i32, String))`
     /// - `DnsApiError::UnknownErrorCode((i32, String))`
     /// - `DnsApiError::Reqwest(reqwest::Error)`
+    /// - `DnsApiError::CircuitOpen(Duration)`
+    /// - `DnsApiError::UnexpectedResponse(String)`
+    /// - `DnsApiError::ServiceUnavailable(String)`
+    ///
+    /// Errors that happen once the request was sent come wrapped in `DnsApiError::Request`, with the
+    /// identifier of the request, so match on `DnsApiError::inner` to tell them apart.
     /// 
     /// # Examples
     ///
     /// ```
-    /// let mut service = anondns_api::api::Service::new();
-    /// let token = service.register("example_subdomain", std::net::Ipv4Addr::new(127, 0, 0, 1)).unwrap();
+    /// let service = anondns_api::api::Service::new();
+    /// let token = service.register("example_subdomain", std::net::Ipv4Addr::new(203, 0, 113, 1)).unwrap().token;
     /// ```
-    pub fn register(&mut self, subdomain: &str, target: Ipv4Addr) -> Result<Token, error::DnsApiError> {
-        let url = format!("https://anondns.net/api/register/{}.anondns.net/a/{}", subdomain, target.to_string());
-        let json: Response = self.client.get(url)
-            .send()?
-            .json()?;
-
-        return match json.code {
-            0 => Ok(json.token.unwrap()),
-            1 => Err(error::DnsApiError::BadRequest((1, json.data))),
-            v => Err(error::DnsApiError::UnknownErrorCode((v, json.data)))
-        };
+    pub fn register(&self, subdomain: &str, target: impl Into<IpAddr>) -> Result<RegisterOutcome, error::DnsApiError> {
+        let target = target.into();
+        let result = self.register_request(subdomain, target);
+
+        match &result {
+            Ok(outcome) => self.audit(subdomain, history::Operation::Register, target, Some(&outcome.token), Some(&outcome.request_id), None),
+            Err(err) => self.audit(subdomain, history::Operation::Register, target, None, err.request_id(), Some(err))
+        }
+        result
+    }
+
+    fn register_request(&self, subdomain: &str, target: IpAddr) -> Result<RegisterOutcome, error::DnsApiError> {
+        validate::subdomain(subdomain)?;
+        validate::not_reserved(subdomain, &self.reserved)?;
+        validate::target(target, self.allow_private)?;
+
+        let request = protocol::Operation::Register { subdomain, target }.request_in(self.version, &self.zone);
+        let (json, request_id, timing) = self.call(&request, target)?;
+
+        let tag = |err: error::DnsApiError| err.for_request(&request_id);
+        let json = json.into_result().map_err(tag)?;
+        let token = json.token.clone().ok_or_else(|| unexpected("registration succeeded without a token")).map_err(tag)?;
+        if self.strict && (token.len() != 32 || !token.chars().all(|c| c.is_ascii_alphanumeric())) {
+            return Err(tag(unexpected("token is not a 32-character string")));
+        }
+
+        self.remember(subdomain, history::Operation::Register, None, target);
+        Ok(RegisterOutcome {
+            token,
+            record: Record::from(&json),
+            sandbox: self.sandbox,
+            dry_run: self.dry_run,
+            request_id,
+            timing
+        })
     }
 
-    /// Updates the redirect target of the specified subdomain and returns the new target `Ipv4Addr` upon success
+    /// Updates the redirect target of the specified subdomain and returns the new and previous targets upon success
+    ///
+    /// If a `RecordCache` is attached and already holds `target` for the subdomain, no request is sent.
     ///
     /// # Arguments
     ///
     /// * `subdomain` - A string slice that holds the DNS subdomain to update
-    /// * `target` - The new target Ipv4Addr the subdomain will redirect to
+    /// * `target` - The new address the subdomain will redirect to, either an `Ipv4Addr` (A) or `Ipv6Addr` (AAAA)
+    /// * `token` - The token returned when the subdomain was registered, as any string-like type
     ///
     /// # Errors
     /// 
     /// This function may return one of the following error variants:
+    /// - `DnsApiError::InvalidSubdomain(String)`
+    /// - `DnsApiError::InvalidTarget(String)`
     /// - `DnsApiError::BadRequest((i32, String))`
     /// - `DnsApiError::UnknownErrorCode((i32, String))`
     /// - `DnsApiError::Reqwest(reqwest::Error)`
     /// - `DnsApiError::AddressParse(std::net::AddrParseError)`
+    /// - `DnsApiError::CircuitOpen(Duration)`
+    /// - `DnsApiError::UnexpectedResponse(String)`
+    /// - `DnsApiError::ServiceUnavailable(String)`
+    ///
+    /// Errors that happen once the request was sent come wrapped in `DnsApiError::Request`, with the
+    /// identifier of the request, so match on `DnsApiError::inner` to tell them apart.
     /// 
     /// # Examples
     ///
     /// ```
-    /// let mut service = anondns_api::api::Service::new();
-    /// let token = service.register("example_subdomain", std::net::Ipv4Addr::new(127, 0, 0, 1));
-    /// let result = service.update("example_subdomain", std::net::Ipv4Addr::new(255, 255, 255, 255), String::from("example_token"));
+    /// let service = anondns_api::api::Service::new();
+    /// let token = service.register("example_subdomain", std::net::Ipv4Addr::new(203, 0, 113, 1));
+    /// let result = service.update("example_subdomain", std::net::Ipv4Addr::new(203, 0, 113, 2), "example_token");
+    /// if let Ok(outcome) = result {
+    ///     println!("changed from {:?} to {}", outcome.previous, outcome.target);
+    /// }
+    /// ```
+    pub fn update(&self, subdomain: &str, target: impl Into<IpAddr>, token: impl AsRef<str>) -> Result<UpdateOutcome, error::DnsApiError> {
+        self.update_audited(subdomain, target.into(), token.as_ref(), false)
+    }
+
+    /// Updates the subdomain only if it currently points to `expected`
+    ///
+    /// The current addresses are read from DNS, since the API cannot read a record back, so two updaters
+    /// that each only move the record away from a known address stop overwriting each other. DNS can lag
+    /// behind a recent update, so this is a guard against ping-ponging rather than an atomic operation.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DnsApiError::ConditionFailed(String)` if DNS does not serve `expected` for the subdomain,
+    /// `DnsApiError::Io(std::io::Error)` if the name could not be resolved, or any error of `update`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::net::Ipv4Addr;
+    ///
+    /// let service = anondns_api::api::Service::new();
+    /// let result = service.update_if("example_subdomain", Ipv4Addr::new(203, 0, 113, 1), Ipv4Addr::new(203, 0, 113, 2), "example_token");
+    /// ```
+    pub fn update_if(&self, subdomain: &str, expected: impl Into<IpAddr>, target: impl Into<IpAddr>, token: impl AsRef<str>) -> Result<UpdateOutcome, error::DnsApiError> {
+        let expected = expected.into();
+        let current = self.lookup(subdomain)?;
+        if !current.contains(&expected) {
+            let current: Vec<String> = current.iter().map(IpAddr::to_string).collect();
+            return Err(error::DnsApiError::ConditionFailed(format!("{} points to [{}], not {}", subdomain, current.join(", "), expected)));
+        }
+
+        self.update(subdomain, target, token)
+    }
+
+    /// Points both the A and the AAAA record of the subdomain at the given addresses
+    ///
+    /// The A record is updated first. If updating the AAAA record then fails, the A record is set back to
+    /// its previous address when the `RecordCache` knows it, so the two records do not end up pointing at
+    /// different hosts. The rollback is best-effort and its own failure is ignored in favour of the
+    /// original error. The cache keeps the A and AAAA records apart, so the previous A address is known
+    /// whichever record was updated last. Both updates are always sent rather than skipped when unchanged.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of whichever update failed, see `update`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::net::{Ipv4Addr, Ipv6Addr};
+    ///
+    /// let service = anondns_api::api::Service::new();
+    /// let v6 = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+    /// let (a, aaaa) = service.update_dual_stack("example_subdomain", Ipv4Addr::new(203, 0, 113, 1), v6, "example_token").unwrap();
+    /// ```
+    pub fn update_dual_stack(&self, subdomain: &str, v4: Ipv4Addr, v6: Ipv6Addr, token: impl AsRef<str>) -> Result<(UpdateOutcome, UpdateOutcome), error::DnsApiError> {
+        let token = token.as_ref();
+        let a = self.update_audited(subdomain, v4.into(), token, true)?;
+
+        match self.update_audited(subdomain, v6.into(), token, true) {
+            Ok(aaaa) => Ok((a, aaaa)),
+            Err(err) => {
+                if let Some(previous) = a.previous.filter(|previous| *previous != a.target) {
+                    log::warn!("updating the AAAA record of {} failed, restoring its A record to {}", subdomain, previous);
+                    let _ = self.update_audited(subdomain, previous, token, true);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Registers the subdomain with an A record for `v4`, then points its AAAA record at `v6` with the
+    /// returned token
+    ///
+    /// The API cannot delete records, so a registration cannot be rolled back. If setting the AAAA record
+    /// fails, the registration is still returned along with that error, so its token is not lost and the
+    /// AAAA record can be set again later with `update`.
+    ///
+    /// # Errors
+    ///
+    /// Returns any error of `register` if the registration failed, in which case nothing was created.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::net::{Ipv4Addr, Ipv6Addr};
+    ///
+    /// let service = anondns_api::api::Service::new();
+    /// let v6 = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+    /// let (registered, aaaa) = service.register_dual_stack("example_subdomain", Ipv4Addr::new(203, 0, 113, 1), v6).unwrap();
+    /// if let Err(err) = aaaa {
+    ///     eprintln!("registered with token {}, but the AAAA record was not set: {}", registered.token, err);
+    /// }
+    /// ```
+    pub fn register_dual_stack(&self, subdomain: &str, v4: Ipv4Addr, v6: Ipv6Addr) -> Result<(RegisterOutcome, Result<UpdateOutcome, error::DnsApiError>), error::DnsApiError> {
+        let registered = self.register(subdomain, v4)?;
+        let aaaa = self.update_audited(subdomain, v6.into(), &registered.token, true);
+        if let Err(err) = &aaaa {
+            log::warn!("registered {}, but setting its AAAA record failed: {}", subdomain, err);
+        }
+
+        Ok((registered, aaaa))
+    }
+
+    /// Sets the subdomain to the target it already has, so the provider sees it as active
+    ///
+    /// AnonDNS does not document whether inactive records expire, and has no renew endpoint, so renewing
+    /// sends an ordinary update that is never skipped by the cache. The target is taken from the
+    /// `RecordCache` if it holds the subdomain, preferring its A record, and from DNS otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DnsApiError::Io(std::io::Error)` if the target is not cached and the name could not be
+    /// resolved, or any error of `update`.
+    pub fn renew(&self, subdomain: &str, token: impl AsRef<str>) -> Result<UpdateOutcome, error::DnsApiError> {
+        let cached = self.cache.as_ref().and_then(|cache| lock(cache).get_all(subdomain).next().map(|record| record.target));
+        let target = match cached {
+            Some(target) => target,
+            None => *self.lookup(subdomain)?.first()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "subdomain has no addresses"))?
+        };
+
+        self.update_audited(subdomain, target, token.as_ref(), true)
+    }
+
+    /// Renews every cached record last confirmed more than `max_age` ago whose token `state` holds
+    ///
+    /// Call this periodically, with `max_age` comfortably below the provider's inactivity cutoff, to
+    /// keep records from expiring. The A and AAAA records of a subdomain are renewed separately, each
+    /// to its own cached target. Returns each renewed subdomain with the result of renewing it.
+    pub fn renew_due(&self, max_age: Duration, state: &State) -> Vec<(String, Result<UpdateOutcome, error::DnsApiError>)> {
+        let due: Vec<(ManagedRecord, Token)> = self.records()
+            .filter(|record| record.updated_at.elapsed().is_ok_and(|age| age > max_age))
+            .filter_map(|record| {
+                let token = state.record(&record.name)?.token.clone()?;
+                Some((record, token))
+            })
+            .collect();
+
+        due.into_iter()
+            .map(|(record, token)| {
+                let result = self.update_audited(&record.name, record.target, &token, true);
+                (record.name, result)
+            })
+            .collect()
+    }
+
+    // Updates even if the cache already holds the target, for records DNS shows to have drifted from it
+    pub(crate) fn update_forced(&self, subdomain: &str, target: IpAddr, token: &str) -> Result<UpdateOutcome, error::DnsApiError> {
+        self.update_audited(subdomain, target, token, true)
+    }
+
+    fn update_audited(&self, subdomain: &str, target: IpAddr, token: &str, force: bool) -> Result<UpdateOutcome, error::DnsApiError> {
+        let result = self.update_request(subdomain, target, token, force);
+
+        match &result {
+            Ok(outcome) => self.audit(subdomain, history::Operation::Update, target, Some(token), outcome.request_id.as_ref(), None),
+            Err(err) => self.audit(subdomain, history::Operation::Update, target, Some(token), err.request_id(), Some(err))
+        }
+        result
+    }
+
+    fn update_request(&self, subdomain: &str, target: IpAddr, token: &str, force: bool) -> Result<UpdateOutcome, error::DnsApiError> {
+        validate::subdomain(subdomain)?;
+        validate::target(target, self.allow_private)?;
+
+        let previous = self.cache.as_ref().and_then(|cache| lock(cache).get(subdomain, record_type(&target)).map(|record| record.target));
+        if !force && previous == Some(target) {
+            return Ok(UpdateOutcome { target, previous, record: Record::default(), sandbox: self.sandbox, dry_run: self.dry_run, request_id: None, timing: None });
+        }
+
+        let request = protocol::Operation::Update { subdomain, token, target }.request_in(self.version, &self.zone);
+        let (json, request_id, timing) = self.call(&request, target)?;
+
+        let tag = |err: error::DnsApiError| err.for_request(&request_id);
+        let json = json.into_result().map_err(tag)?;
+        let confirmed = json.data.parse().map_err(|err: std::net::AddrParseError| tag(err.into()))?;
+        if self.strict && confirmed != target {
+            return Err(tag(unexpected(&format!("update to {} was confirmed as {}", target, confirmed))));
+        }
+        self.remember(subdomain, history::Operation::Update, previous, confirmed);
+        Ok(UpdateOutcome { target: confirmed, previous, record: Record::from(&json), sandbox: self.sandbox, dry_run: self.dry_run, request_id: Some(request_id), timing })
+    }
+
+    /// Sends a registration request and returns the response without interpreting it
+    ///
+    /// This is an escape hatch for responses the typed API does not model yet. The request goes through
+    /// the configured endpoints, headers and middleware, but the subdomain and target are not validated,
+    /// the circuit breaker is bypassed and nothing is cached or recorded in the history and audit log.
+    /// On a `dry_run` service nothing is sent, and the envelope holds the response a successful call
+    /// would get.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DnsApiError::Reqwest(reqwest::Error)` if no endpoint could be reached. Error status codes
+    /// other than 5xx and 429, and error codes in the body, are returned as part of the envelope.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let service = anondns_api::api::Service::new();
+    /// let envelope = service.register_raw("example_subdomain", std::net::Ipv4Addr::new(203, 0, 113, 1)).unwrap();
+    /// println!("{} {:?}", envelope.status, String::from_utf8_lossy(&envelope.body));
+    /// ```
+    pub fn register_raw(&self, subdomain: &str, target: impl Into<IpAddr>) -> Result<Envelope, error::DnsApiError> {
+        let target = target.into();
+        self.call_raw(&protocol::Operation::Register { subdomain, target }.request_in(self.version, &self.zone), target)
+    }
+
+    /// Sends an update request and returns the response without interpreting it, see `register_raw`
+    pub fn update_raw(&self, subdomain: &str, target: impl Into<IpAddr>, token: impl AsRef<str>) -> Result<Envelope, error::DnsApiError> {
+        let target = target.into();
+        self.call_raw(&protocol::Operation::Update { subdomain, token: token.as_ref(), target }.request_in(self.version, &self.zone), target)
+    }
+
+    /// Detects this host's public address and registers the subdomain to it
+    ///
+    /// The address is detected over the service's `local_address` and `address_family`, so it is the
+    /// address API requests come from. See `ip::public_ip` for how it is detected, and `register` for
+    /// the errors this may return besides those of detection.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let service = anondns_api::api::Service::new();
+    /// let token = service.register_with_public_ip("example_subdomain").unwrap().token;
+    /// ```
+    pub fn register_with_public_ip(&self, subdomain: &str) -> Result<RegisterOutcome, error::DnsApiError> {
+        let target = ip::detect(self.client_config.local_address, self.client_config.family)?;
+        self.register(subdomain, target)
+    }
+
+    /// Detects this host's public address and updates the subdomain to it, see `register_with_public_ip`
+    pub fn update_with_public_ip(&self, subdomain: &str, token: impl AsRef<str>) -> Result<UpdateOutcome, error::DnsApiError> {
+        let target = ip::detect(self.client_config.local_address, self.client_config.family)?;
+        self.update(subdomain, target, token)
+    }
+
+    /// Resolves the subdomain through the system resolver and returns the addresses it currently points to
+    ///
+    /// The API has no endpoint for reading a record back, so this reflects what DNS serves, which may lag
+    /// behind a recent update until cached answers expire.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DnsApiError::Io(std::io::Error)` if the name could not be resolved.
+    pub fn lookup(&self, subdomain: &str) -> Result<Vec<IpAddr>, error::DnsApiError> {
+        self.lookup_with(subdomain, &Resolver::System)
+    }
+
+    /// Resolves the subdomain through the given resolver, see `lookup`
+    pub fn lookup_with(&self, subdomain: &str, resolver: &Resolver) -> Result<Vec<IpAddr>, error::DnsApiError> {
+        resolver.lookup(&format!("{}.{}", subdomain, self.zone))
+    }
+
+    /// Returns an iterator that resolves the subdomain every `interval` and yields its addresses when they change
+    ///
+    /// See `Watch` for details.
+    pub fn watch(&self, subdomain: &str, interval: Duration) -> Watch<'_> {
+        Watch::new(self, subdomain, interval)
+    }
+
+    /// Returns a handle for the given subdomain
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let service = anondns_api::api::Service::new();
+    /// let subdomain = service.subdomain("example_subdomain");
+    /// let (record, _) = subdomain.register(std::net::Ipv4Addr::new(203, 0, 113, 1)).unwrap();
+    /// record.update(std::net::Ipv4Addr::new(203, 0, 113, 2)).unwrap();
+    ///
+    /// let existing = service.subdomain("other_subdomain").authorize("example_token");
+    /// existing.update(std::net::Ipv4Addr::new(203, 0, 113, 1)).unwrap();
     /// ```
-    pub fn update(&mut self, subdomain: &str, target: Ipv4Addr, token: Token) -> Result<Ipv4Addr, error::DnsApiError> {
-        let url = format!("https://anondns.net/api/set/{}.anondns.net/{}/a/{}", subdomain, token, target.to_string());
-        let json: Response = self.client.get(url)
-            .send()?
-            .json()?;
-
-        return match json.code {
-            0 => Ok(json.data.parse()?),
-            1 => Err(error::DnsApiError::BadRequest((1, json.data))),
-            v => Err(error::DnsApiError::UnknownErrorCode((v, json.data)))
+    pub fn subdomain(&self, name: &str) -> Subdomain<'_> {
+        Subdomain {
+            service: self,
+            name: name.to_owned()
+        }
+    }
+
+    /// Returns the API version this service speaks
+    pub fn api_version(&self) -> ApiVersion {
+        self.version
+    }
+
+    /// Returns the API base URLs this service uses, primary first
+    pub fn endpoints(&self) -> &[String] {
+        &self.endpoints
+    }
+
+    /// Returns the parent zone records live under
+    pub fn zone(&self) -> &str {
+        &self.zone
+    }
+
+    // The cache, history and audit log are local bookkeeping, so failing to persist them must not fail an operation
+    // that succeeded on the server
+    fn remember(&self, subdomain: &str, operation: history::Operation, old: Option<IpAddr>, target: IpAddr) {
+        if self.dry_run {
+            return;
+        }
+        if let Some(cache) = &self.cache {
+            let _ = lock(cache).insert(subdomain, target);
+        }
+        if let Some(history) = &self.history {
+            let _ = history.append(&HistoryEntry {
+                at: std::time::SystemTime::now(),
+                subdomain: subdomain.to_owned(),
+                operation,
+                old,
+                new: target
+            });
+        }
+    }
+
+    // Succeeds if any endpoint answers an HTTP request, whatever its status
+    pub(crate) fn ping(&self) -> reqwest::Result<()> {
+        self.send("/", &RequestId::generate()).map(|_| ())
+    }
+
+    fn audit(&self, subdomain: &str, operation: history::Operation, target: IpAddr, token: Option<&str>, request_id: Option<&RequestId>, err: Option<&error::DnsApiError>) {
+        if self.dry_run {
+            return;
+        }
+        if let Some(audit) = &self.audit {
+            let _ = audit.append(&AuditEntry {
+                at: SystemTime::now(),
+                subdomain: subdomain.to_owned(),
+                operation,
+                target,
+                token: token.map(audit::redact),
+                request_id: request_id.map(RequestId::to_string),
+                error: err.map(error::DnsApiError::to_string)
+            });
+        }
+    }
+
+    fn call(&self, request: &protocol::Request, target: IpAddr) -> Result<(protocol::Response, RequestId, Option<Timing>), error::DnsApiError> {
+        if self.dry_run {
+            let (json, id) = self.simulate(target);
+            return Ok((json, id, None));
+        }
+        let call = self.breaker.as_ref().map(|breaker| lock(breaker).check()).transpose()?;
+
+        let id = RequestId::generate();
+        let policy = self.options.retry.unwrap_or(self.retry);
+        let mut attempt = 0;
+        let started = Instant::now();
+        let result = loop {
+            let sent = Instant::now();
+            match self.send(&request.path, &id) {
+                Err(err) if attempt < policy.retries && (err.is_connect() || err.is_timeout() || err.is_status()) => {
+                    log::info!("request {} failed, retrying: {}", id, err);
+                    thread::sleep(policy.delay(attempt));
+                    attempt += 1;
+                },
+                Err(err) if err.is_redirect() => break Err(unexpected(&err.source().map_or_else(|| err.to_string(), ToString::to_string))),
+                result => break result.map_err(error::DnsApiError::from).and_then(|response| {
+                    let response_time = sent.elapsed();
+                    let content_type = response.headers().get(reqwest::header::CONTENT_TYPE)
+                        .and_then(|value| value.to_str().ok())
+                        .map(str::to_owned);
+                    let body = self.read_body(response)?;
+                    let timing = Timing { response: response_time, total: started.elapsed(), attempts: attempt + 1 };
+                    let json = protocol::parse_with_content_type(content_type.as_deref(), &body)?;
+                    if self.strict && !protocol::has_integer_code(&body) {
+                        return Err(unexpected("the response code is missing or not an integer"));
+                    }
+                    Ok((json, timing))
+                })
+            }
         };
+
+        if let (Some(breaker), Some(call)) = (&self.breaker, call) {
+            let mut breaker = lock(breaker);
+            match &result {
+                Ok(_) => breaker.record_success(call),
+                Err(_) => breaker.record_failure(call)
+            }
+        }
+
+        let result = match result {
+            Ok((json, _)) if self.strict && !json.unknown.is_empty() => {
+                Err(unexpected(&format!("unknown fields {}", json.unknown_fields().join(", "))))
+            },
+            result => result
+        };
+        match result {
+            Ok((json, timing)) => Ok((json, id, Some(timing))),
+            Err(err) => {
+                log::warn!("request {} failed: {}", id, err);
+                Err(err.for_request(&id))
+            }
+        }
+    }
+
+    // The response a successful call would get, which serves as both a registration and an update
+    fn simulate(&self, target: IpAddr) -> (protocol::Response, RequestId) {
+        let id = RequestId::generate();
+        log::info!("request {}: dry run, not sent", id);
+
+        let response = protocol::Response {
+            code: 0,
+            data: target.to_string(),
+            name: None,
+            status: None,
+            record_type: Some(record_type(&target).to_owned()),
+            token: Some(DRY_RUN_TOKEN.to_owned()),
+            updated: None,
+            unknown: HashMap::new()
+        };
+        (response, id)
+    }
+
+    fn call_raw(&self, request: &protocol::Request, target: IpAddr) -> Result<Envelope, error::DnsApiError> {
+        if self.dry_run {
+            let (response, id) = self.simulate(target);
+            let body = serde_json::json!({
+                "code": response.code,
+                "data": response.data,
+                "type": response.record_type,
+                "token": response.token
+            });
+            let mut headers = HeaderMap::new();
+            headers.insert(reqwest::header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            return Ok(Envelope { status: reqwest::StatusCode::OK, headers, body: body.to_string().into_bytes(), request_id: id });
+        }
+
+        let id = RequestId::generate();
+        let tag = |err: error::DnsApiError| err.for_request(&id);
+        let response = self.send(&request.path, &id).map_err(|err| tag(err.into()))?;
+
+        Ok(Envelope {
+            status: response.status(),
+            headers: response.headers().clone(),
+            body: self.read_body(response).map_err(tag)?,
+            request_id: id
+        })
+    }
+
+    // Reads at most one byte past the limit, so an oversized body is detected without buffering all of it
+    fn read_body(&self, response: reqwest::blocking::Response) -> Result<Vec<u8>, error::DnsApiError> {
+        let limit = self.max_response_size;
+        let too_large = || unexpected(&format!("response body exceeds {} bytes", limit));
+        if response.content_length().is_some_and(|len| len > limit as u64) {
+            return Err(too_large());
+        }
+
+        let mut body = Vec::new();
+        response.take(limit as u64 + 1).read_to_end(&mut body)?;
+        if body.len() > limit {
+            return Err(too_large());
+        }
+        Ok(body)
+    }
+
+    // Returns the HTTP client, initializing it on first use. Threads racing to initialize it may each
+    // build a client, but only the first one stored is ever used.
+    fn client(&self) -> reqwest::Result<&reqwest::blocking::Client> {
+        if let Some(client) = self.client.get() {
+            return Ok(client);
+        }
+
+        let client = self.client_config.build()?;
+        Ok(self.client.get_or_init(|| client))
+    }
+
+    // Paths carry tokens, so only endpoints are logged
+    fn send(&self, path: &str, id: &RequestId) -> reqwest::Result<reqwest::blocking::Response> {
+        let client = self.client()?;
+        let mut endpoints = self.endpoints.iter().peekable();
+
+        loop {
+            let endpoint = endpoints.next().expect("service has no endpoints");
+            log::debug!("request {}: sending to {}", id, endpoint);
+            let mut request = client.get(format!("{}{}", endpoint, path))
+                .headers(self.headers.clone());
+            if let Some(header) = &self.request_id_header {
+                request = request.header(header.clone(), id.as_str());
+            }
+            if let Some(timeout) = self.options.timeout {
+                request = request.timeout(timeout);
+            }
+
+            let result = request.build()
+                .and_then(|mut request| {
+                    self.middleware.on_request(&mut request);
+                    client.execute(request)
+                })
+                .and_then(|response| {
+                    log::debug!("request {}: {} responded {}", id, endpoint, response.status());
+                    self.middleware.on_response(&response);
+                    if response.status().is_server_error() || response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                        response.error_for_status()
+                    } else {
+                        Ok(response)
+                    }
+                });
+
+            match result {
+                Err(err) if endpoints.peek().is_some() && (err.is_connect() || err.is_timeout() || err.is_status()) => continue,
+                result => return result
+            }
+        }
+    }
+}
+
+// The settings used to build the HTTP client when none was supplied
+#[derive(Debug, Clone, Default)]
+struct ClientConfig {
+    timeout: Option<Duration>,
+    local_address: Option<IpAddr>,
+    family: AddressFamily,
+    overrides: Vec<(String, Vec<SocketAddr>)>,
+    pinned: Vec<reqwest::Certificate>,
+    accept_invalid_certs: bool,
+    pool_max_idle: Option<usize>,
+    pool_idle_timeout: Option<Option<Duration>>,
+    tcp_keepalive: Option<Duration>,
+    max_redirects: Option<usize>,
+    // The hosts of the service's endpoints, filled in by `ServiceBuilder::build`
+    endpoint_hosts: Vec<String>
+}
+
+impl ClientConfig {
+    fn build(&self) -> reqwest::Result<reqwest::blocking::Client> {
+        let mut builder = reqwest::blocking::Client::builder();
+        #[cfg(feature = "hickory")]
+        {
+            builder = builder.hickory_dns(true);
+        }
+        #[cfg(feature = "rustls")]
+        {
+            builder = builder.use_rustls_tls();
+        }
+        #[cfg(feature = "compression")]
+        {
+            builder = builder.gzip(true).brotli(true);
+        }
+        for (host, addresses) in &self.overrides {
+            builder = builder.resolve_to_addrs(host, addresses);
+        }
+        if !self.pinned.is_empty() {
+            builder = builder.tls_built_in_root_certs(false);
+            for certificate in &self.pinned {
+                builder = builder.add_root_certificate(certificate.clone());
+            }
+        }
+        if self.accept_invalid_certs {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(max) = self.pool_max_idle {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if let Some(interval) = self.tcp_keepalive {
+            builder = builder.tcp_keepalive(interval);
+        }
+        // Binding to the unspecified address of a family leaves only that family's addresses to connect to
+        let local_address = self.local_address.or(match self.family {
+            AddressFamily::Any => None,
+            AddressFamily::V4 => Some(Ipv4Addr::UNSPECIFIED.into()),
+            AddressFamily::V6 => Some(Ipv6Addr::UNSPECIFIED.into())
+        });
+        if let Some(address) = local_address {
+            builder = builder.local_address(address);
+        }
+
+        // Request paths carry tokens, so they must never be replayed to a host nobody configured
+        let max_redirects = self.max_redirects.unwrap_or(10);
+        let hosts = self.endpoint_hosts.clone();
+        builder = builder.redirect(reqwest::redirect::Policy::custom(move |attempt| {
+            let host = attempt.url().host_str().unwrap_or_default().to_owned();
+            if attempt.previous().len() > max_redirects {
+                attempt.error(format!("more than {} redirects", max_redirects))
+            } else if hosts.contains(&host) || host == "anondns.net" || host.ends_with(".anondns.net") {
+                attempt.follow()
+            } else {
+                attempt.error(format!("redirect to {} is not allowed", host))
+            }
+        }));
+        builder.build()
+    }
+}
+
+fn unexpected(reason: &str) -> error::DnsApiError {
+    error::DnsApiError::UnexpectedResponse(reason.to_owned())
+}
+
+fn is_production(endpoint: &str) -> bool {
+    reqwest::Url::parse(endpoint).ok()
+        .and_then(|url| url.host_str().map(|host| host == "anondns.net" || host.ends_with(".anondns.net")))
+        .unwrap_or(false)
+}
+
+// A panic while holding one of these locks cannot leave the breaker or cache in an unusable state
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// A handle to a single subdomain, created by `Service::subdomain`
+///
+/// Operations that need no token, registering and looking up, are available here. Updates require an
+/// `AuthorizedRecord`, which is obtained by registering or by supplying a known token to `authorize`.
+#[derive(Debug, Clone)]
+pub struct Subdomain<'a> {
+    service: &'a Service,
+    name: String
+}
+
+impl<'a> Subdomain<'a> {
+    /// Returns the name of the subdomain
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Registers the subdomain and returns a record authorized with the new token, alongside the outcome
+    ///
+    /// See `Service::register` for the errors this may return.
+    pub fn register(&self, target: impl Into<IpAddr>) -> Result<(AuthorizedRecord<'a>, RegisterOutcome), error::DnsApiError> {
+        let outcome = self.service.register(&self.name, target)?;
+
+        Ok((self.authorize(outcome.token.clone()), outcome))
+    }
+
+    /// Returns a record for the subdomain authorized with an existing token
+    pub fn authorize(&self, token: impl Into<Token>) -> AuthorizedRecord<'a> {
+        AuthorizedRecord {
+            service: self.service,
+            name: self.name.clone(),
+            token: token.into()
+        }
+    }
+
+    /// Resolves the subdomain, see `Service::lookup`
+    pub fn lookup(&self) -> Result<Vec<IpAddr>, error::DnsApiError> {
+        self.service.lookup(&self.name)
+    }
+}
+
+/// A subdomain together with the token that authorizes changes to it
+#[derive(Debug, Clone)]
+pub struct AuthorizedRecord<'a> {
+    service: &'a Service,
+    name: String,
+    token: Token
+}
+
+impl AuthorizedRecord<'_> {
+    /// Returns the name of the subdomain
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the token authorizing changes to the subdomain
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Updates the subdomain, see `Service::update`
+    pub fn update(&self, target: impl Into<IpAddr>) -> Result<UpdateOutcome, error::DnsApiError> {
+        self.service.update(&self.name, target, &self.token)
+    }
+
+    /// Updates the subdomain if it currently points to `expected`, see `Service::update_if`
+    pub fn update_if(&self, expected: impl Into<IpAddr>, target: impl Into<IpAddr>) -> Result<UpdateOutcome, error::DnsApiError> {
+        self.service.update_if(&self.name, expected, target, &self.token)
+    }
+
+    /// Resolves the subdomain, see `Service::lookup`
+    pub fn lookup(&self) -> Result<Vec<IpAddr>, error::DnsApiError> {
+        self.service.lookup(&self.name)
+    }
+
+    /// Resolves the subdomain through the given resolver, see `Service::lookup_with`
+    pub fn lookup_with(&self, resolver: &Resolver) -> Result<Vec<IpAddr>, error::DnsApiError> {
+        self.service.lookup_with(&self.name, resolver)
     }
 }
\ No newline at end of file
@@ -0,0 +1,63 @@
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::api::IpAddr;
+use crate::history::Operation;
+use crate::lock;
+
+/// A single register or update attempt, successful or not
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub at: SystemTime,
+    pub subdomain: String,
+    pub operation: Operation,
+    /// The target that was requested
+    pub target: IpAddr,
+    /// The token used or issued, redacted to its first four characters
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// The error the attempt failed with, or `None` if it succeeded
+    #[serde(default)]
+    pub error: Option<String>
+}
+
+/// An append-only on-disk log of every register and update attempt, one JSON object per line
+///
+/// Unlike `History`, which only keeps successful changes, the audit log also records failures, so it
+/// answers who tried to change a record and when. Tokens are redacted before they are written, so the
+/// log can be kept and shared without exposing them.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf
+}
+
+impl AuditLog {
+    /// Uses the audit log stored at `path`, which is created on the first append
+    pub fn open<P: Into<PathBuf>>(path: P) -> Self {
+        AuditLog { path: path.into() }
+    }
+
+    /// Appends an entry to the log
+    pub fn append(&self, entry: &AuditEntry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        lock::append(&self.path, &line)
+    }
+
+    /// Returns every recorded entry, oldest first, skipping lines that cannot be parsed
+    pub fn entries(&self) -> io::Result<Vec<AuditEntry>> {
+        let contents = lock::read(&self.path)?.unwrap_or_default();
+
+        Ok(contents.lines().filter_map(|line| serde_json::from_str(line).ok()).collect())
+    }
+}
+
+/// Shortens a token to its first four characters, so entries can be matched to a token without revealing it
+pub fn redact(token: &str) -> String {
+    let prefix: String = token.chars().take(4).collect();
+    format!("{}...", prefix)
+}
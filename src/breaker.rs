@@ -0,0 +1,124 @@
+use std::time::{Duration, Instant};
+
+use crate::error;
+
+/// The state of a `CircuitBreaker`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Calls go through as normal
+    Closed,
+    /// Calls are rejected until the cool-down period has elapsed
+    Open,
+    /// The cool-down has elapsed and the next call decides whether the breaker closes or opens again
+    HalfOpen
+}
+
+/// Short-circuits API calls after repeated failures
+///
+/// Once `threshold` consecutive calls have failed to get a usable response from the API, every call is
+/// rejected with `DnsApiError::CircuitOpen` for the cool-down period. After that a single trial call is
+/// let through, and other calls are rejected until it completes: if it succeeds the breaker closes
+/// again, otherwise it re-opens for another cool-down.
+///
+/// With `backoff`, every failed trial call doubles the cool-down, up to a cap, so an API that stays down
+/// is tried less and less often. A success resets it. Each call is told whether it is the trial when it
+/// is let through, so calls that started before the breaker opened and complete late change neither the
+/// state, the cool-down nor when it ends.
+///
+/// Only failures to talk to the API count towards the threshold. Requests the API rejects, such as
+/// registering a name that is already taken, do not.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    max_cooldown: Duration,
+    current: Duration,
+    failures: u32,
+    opened_at: Option<Instant>,
+    trial: bool
+}
+
+// Handed out by `CircuitBreaker::check` for each call it lets through, and passed back with the
+// call's outcome so that only the trial call can close or re-open the breaker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Call {
+    trial: bool
+}
+
+impl CircuitBreaker {
+    /// Creates a closed breaker that opens after `threshold` consecutive failures and stays open for `cooldown`
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold: threshold.max(1),
+            cooldown,
+            max_cooldown: cooldown,
+            current: cooldown,
+            failures: 0,
+            opened_at: None,
+            trial: false
+        }
+    }
+
+    /// Doubles the cool-down after each failed trial call, up to `max_cooldown`
+    pub fn backoff(mut self, max_cooldown: Duration) -> Self {
+        self.max_cooldown = max_cooldown.max(self.cooldown);
+        self
+    }
+
+    /// Returns the current state of the breaker
+    pub fn state(&self) -> State {
+        match self.opened_at {
+            None => State::Closed,
+            Some(at) if at.elapsed() < self.current => State::Open,
+            Some(_) => State::HalfOpen
+        }
+    }
+
+    /// Returns the cool-down the breaker currently waits for before letting a trial call through
+    pub fn current_cooldown(&self) -> Duration {
+        self.current
+    }
+
+    /// Returns the number of consecutive failures recorded since the last success
+    pub fn consecutive_failures(&self) -> u32 {
+        self.failures
+    }
+
+    // Lets a call through, claiming the trial if the breaker is half-open
+    pub(crate) fn check(&mut self) -> Result<Call, error::DnsApiError> {
+        match (self.state(), self.opened_at) {
+            (State::Open, Some(at)) => Err(error::DnsApiError::CircuitOpen(self.current.saturating_sub(at.elapsed()))),
+            (State::HalfOpen, _) if self.trial => Err(error::DnsApiError::CircuitOpen(Duration::ZERO)),
+            (State::HalfOpen, _) => {
+                self.trial = true;
+                Ok(Call { trial: true })
+            },
+            _ => Ok(Call { trial: false })
+        }
+    }
+
+    pub(crate) fn record_success(&mut self, call: Call) {
+        if !call.trial && self.opened_at.is_some() {
+            return;
+        }
+
+        self.failures = 0;
+        self.opened_at = None;
+        self.trial = false;
+        self.current = self.cooldown;
+    }
+
+    pub(crate) fn record_failure(&mut self, call: Call) {
+        if call.trial {
+            self.failures = self.failures.saturating_add(1);
+            self.trial = false;
+            self.current = self.current.saturating_mul(2).min(self.max_cooldown);
+            self.opened_at = Some(Instant::now());
+        } else if self.opened_at.is_none() {
+            self.failures = self.failures.saturating_add(1);
+            if self.failures >= self.threshold {
+                self.opened_at = Some(Instant::now());
+            }
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use std::fmt;
+use std::io::BufRead;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::api::{IpAddr, RegisterOutcome, Service, UpdateOutcome};
+use crate::error;
+use crate::sync::{Action, DesiredRecord};
+
+/// How far a bulk operation has got, passed to progress callbacks after each record
+#[derive(Debug, Clone, Copy)]
+pub struct Progress<'a> {
+    /// The number of records finished so far, including this one
+    pub done: usize,
+    pub total: usize,
+    /// The subdomain of the record that just finished
+    pub subdomain: &'a str,
+    /// The error the record failed with, `None` if it succeeded
+    pub error: Option<&'a error::DnsApiError>
+}
+
+/// Stops a running bulk operation from another thread, see `Bulk::cancel_with`
+///
+/// Clones share the same flag, so keep one and hand a clone to the operation.
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    /// Creates a handle that has not been cancelled
+    pub fn new() -> Self {
+        Cancel::default()
+    }
+
+    /// Stops operations using this handle from sending any more records
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns `true` once `cancel` has been called
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Runs register and update batches on a bounded number of threads
+///
+/// Each thread sends its own requests, so a large batch is spread over several connections instead of
+/// waiting on one. All threads share the service, including its breaker and cache. Results are returned
+/// in input order.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::net::Ipv4Addr;
+///
+/// let service = anondns_api::api::Service::new();
+/// let records = [("first", Ipv4Addr::new(203, 0, 113, 1).into()), ("second", Ipv4Addr::new(203, 0, 113, 2).into())];
+///
+/// let results = anondns_api::bulk::Bulk::new(&service)
+///     .threads(8)
+///     .on_progress(|progress| eprintln!("{}/{} {}", progress.done, progress.total, progress.subdomain))
+///     .register_all(&records);
+/// ```
+pub struct Bulk<'a> {
+    service: &'a Service,
+    threads: usize,
+    progress: Option<ProgressFn<'a>>,
+    cancel: Cancel
+}
+
+type ProgressFn<'a> = Box<dyn Fn(Progress<'_>) + Sync + 'a>;
+
+// The number of finished items and the result of each, in input order
+type Slots<T> = (usize, Vec<Option<Result<T, error::DnsApiError>>>);
+
+impl fmt::Debug for Bulk<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bulk")
+            .field("service", self.service)
+            .field("threads", &self.threads)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> Bulk<'a> {
+    /// Creates a runner for `service` that uses 4 threads
+    pub fn new(service: &'a Service) -> Self {
+        Bulk { service, threads: 4, progress: None, cancel: Cancel::new() }
+    }
+
+    /// Sets the largest number of records processed at the same time
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Calls `progress` after each record finishes
+    ///
+    /// Calls come from the worker threads one at a time, so `done` only ever grows.
+    pub fn on_progress(mut self, progress: impl Fn(Progress<'_>) + Sync + 'a) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Stops the operation once `cancel` is cancelled
+    ///
+    /// Records already being sent finish normally and keep their results. Every record not sent yet
+    /// fails with `DnsApiError::Cancelled`, so the results show exactly which records changed and which
+    /// remain.
+    pub fn cancel_with(mut self, cancel: &Cancel) -> Self {
+        self.cancel = cancel.clone();
+        self
+    }
+
+    /// Registers every subdomain, returning the results in input order
+    pub fn register_all<S>(&self, records: &[(S, IpAddr)]) -> Vec<Result<RegisterOutcome, error::DnsApiError>>
+    where
+        S: AsRef<str> + Sync
+    {
+        self.run(records, |(subdomain, _)| subdomain.as_ref(), |(subdomain, target)| self.service.register(subdomain.as_ref(), *target))
+    }
+
+    /// Updates every subdomain, returning the results in input order
+    pub fn update_all<S, T>(&self, records: &[(S, IpAddr, T)]) -> Vec<Result<UpdateOutcome, error::DnsApiError>>
+    where
+        S: AsRef<str> + Sync,
+        T: AsRef<str> + Sync
+    {
+        self.run(records, |(subdomain, _, _)| subdomain.as_ref(), |(subdomain, target, token)| self.service.update(subdomain.as_ref(), *target, token))
+    }
+
+    // Applies `f` to every item on a bounded set of scoped threads that each take the next unclaimed item
+    fn run<I, T, N, F>(&self, items: &[I], name: N, f: F) -> Vec<Result<T, error::DnsApiError>>
+    where
+        I: Sync,
+        T: Send,
+        N: Fn(&I) -> &str + Sync,
+        F: Fn(&I) -> Result<T, error::DnsApiError> + Sync
+    {
+        let next = AtomicUsize::new(0);
+        let results: Mutex<Slots<T>> = Mutex::new((0, items.iter().map(|_| None).collect()));
+
+        thread::scope(|scope| {
+            for _ in 0..self.threads.min(items.len()) {
+                scope.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(i) else {
+                        break;
+                    };
+                    let result = if self.cancel.is_cancelled() {
+                        Err(error::DnsApiError::Cancelled)
+                    } else {
+                        f(item)
+                    };
+
+                    let mut results = results.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    results.0 += 1;
+                    if let Some(progress) = &self.progress {
+                        progress(Progress { done: results.0, total: items.len(), subdomain: name(item), error: result.as_ref().err() });
+                    }
+                    results.1[i] = Some(result);
+                });
+            }
+        });
+
+        results.into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .1
+            .into_iter()
+            .map(|result| result.expect("every item is processed before the scope ends"))
+            .collect()
+    }
+}
+
+/// Registers many subdomains using up to `threads` threads, returning the results in input order
+///
+/// This is `Bulk::register_all` without a progress callback.
+pub fn register_all<S>(service: &Service, records: &[(S, IpAddr)], threads: usize) -> Vec<Result<RegisterOutcome, error::DnsApiError>>
+where
+    S: AsRef<str> + Sync
+{
+    Bulk::new(service).threads(threads).register_all(records)
+}
+
+/// Updates many subdomains using up to `threads` threads, returning the results in input order
+///
+/// This is `Bulk::update_all` without a progress callback.
+pub fn update_all<S, T>(service: &Service, records: &[(S, IpAddr, T)], threads: usize) -> Vec<Result<UpdateOutcome, error::DnsApiError>>
+where
+    S: AsRef<str> + Sync,
+    T: AsRef<str> + Sync
+{
+    Bulk::new(service).threads(threads).update_all(records)
+}
+
+/// The result of importing one line, yielded by `Import`
+#[derive(Debug)]
+pub struct Imported {
+    /// The line number in the input, starting at 1
+    pub line: usize,
+    /// The name of the record, or `None` if the line could not be read or parsed
+    pub name: Option<String>,
+    pub result: Result<Action, error::DnsApiError>
+}
+
+/// Registers or updates records read one per line from newline-delimited JSON, see `import`
+#[derive(Debug)]
+pub struct Import<'a, R> {
+    service: &'a Service,
+    lines: std::io::Lines<R>,
+    line: usize
+}
+
+/// Imports records from newline-delimited JSON, one `DesiredRecord` object per line
+///
+/// Each line holds a `name`, a `target` and optionally a `token`. Records with a token are updated and
+/// records without one are registered, so store the tokens in the returned actions. Lines are read and
+/// processed one at a time as the iterator advances, so memory use does not grow with the input. Blank
+/// lines are skipped, and a line that cannot be parsed yields an error without stopping the import.
+///
+/// # Examples
+///
+/// ```no_run
+/// let service = anondns_api::api::Service::new();
+/// let file = std::io::BufReader::new(std::fs::File::open("records.ndjson").unwrap());
+///
+/// for imported in anondns_api::bulk::import(&service, file) {
+///     if let Err(err) = imported.result {
+///         eprintln!("line {}: {}", imported.line, err);
+///     }
+/// }
+/// ```
+pub fn import<R: BufRead>(service: &Service, reader: R) -> Import<'_, R> {
+    Import { service, lines: reader.lines(), line: 0 }
+}
+
+impl<R: BufRead> Iterator for Import<'_, R> {
+    type Item = Imported;
+
+    fn next(&mut self) -> Option<Imported> {
+        loop {
+            let line = self.lines.next()?;
+            self.line += 1;
+
+            let record = match line {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => serde_json::from_str::<DesiredRecord>(&line).map_err(error::DnsApiError::from),
+                Err(err) => Err(err.into())
+            };
+            let imported = match record {
+                Ok(record) => {
+                    let result = match &record.token {
+                        Some(token) => self.service.update(&record.name, record.target, token).map(Action::Updated),
+                        None => self.service.register(&record.name, record.target).map(Action::Registered)
+                    };
+                    Imported { line: self.line, name: Some(record.name), result }
+                },
+                Err(err) => Imported { line: self.line, name: None, result: Err(err) }
+            };
+
+            return Some(imported);
+        }
+    }
+}
\ No newline at end of file
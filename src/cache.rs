@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::api::IpAddr;
+use crate::lock;
+use crate::protocol::record_type;
+
+/// The last target the API confirmed for a record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CachedRecord {
+    pub target: IpAddr,
+    pub confirmed_at: SystemTime
+}
+
+/// An on-disk cache of the last confirmed target of each record
+///
+/// Records are kept per subdomain and record type, so the A and AAAA records of a subdomain are cached
+/// side by side. When attached to a `Service`, every successful register and update is recorded here,
+/// and updates to the target already cached for a record are skipped without calling the API. The cache only knows
+/// about changes made through this crate, so records changed elsewhere should be removed from it.
+///
+/// Writes are locked and replace the file atomically, and every change is merged into the latest contents
+/// on disk, so several processes can share one cache without losing each other's entries.
+#[derive(Debug, Clone)]
+pub struct RecordCache {
+    path: PathBuf,
+    records: BTreeMap<(String, &'static str), CachedRecord>
+}
+
+impl RecordCache {
+    /// Opens the cache stored at `path`, treating a missing file as an empty cache
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let records = lock::read(&path)?.as_deref().map(parse).unwrap_or_default();
+
+        Ok(RecordCache { path, records })
+    }
+
+    /// Returns the cached record of a subdomain with the given record type, `"a"` or `"aaaa"`, if there is one
+    pub fn get(&self, subdomain: &str, record_type: &str) -> Option<&CachedRecord> {
+        let record_type = match record_type {
+            "a" => "a",
+            "aaaa" => "aaaa",
+            _ => return None
+        };
+        self.records.get(&(subdomain.to_owned(), record_type))
+    }
+
+    /// Returns the cached records of a subdomain, the A record first
+    pub fn get_all<'a>(&'a self, subdomain: &'a str) -> impl Iterator<Item = &'a CachedRecord> {
+        ["a", "aaaa"].into_iter().filter_map(move |record_type| self.get(subdomain, record_type))
+    }
+
+    /// Returns an iterator over every cached record and its subdomain, sorted by name and then record type
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &CachedRecord)> {
+        self.records.iter().map(|((name, _), record)| (name.as_str(), record))
+    }
+
+    /// Records `target` as the confirmed target of the subdomain's record of its type and persists the cache
+    pub fn insert(&mut self, subdomain: &str, target: IpAddr) -> io::Result<()> {
+        self.insert_record(subdomain, CachedRecord { target, confirmed_at: SystemTime::now() })
+    }
+
+    /// Stores a record as is, including its confirmation time, and persists the cache
+    pub fn insert_record(&mut self, subdomain: &str, record: CachedRecord) -> io::Result<()> {
+        self.modify(|records| {
+            records.insert((subdomain.to_owned(), record_type(&record.target)), record);
+        })
+    }
+
+    /// Forgets every cached record of a subdomain and persists the cache
+    pub fn remove(&mut self, subdomain: &str) -> io::Result<()> {
+        self.modify(|records| {
+            records.retain(|(name, _), _| name != subdomain);
+        })
+    }
+
+    fn modify(&mut self, change: impl FnOnce(&mut Records)) -> io::Result<()> {
+        self.records = lock::update(&self.path, |current| {
+            let mut records = parse(current);
+            change(&mut records);
+
+            (serialize(&records), records)
+        })?;
+
+        Ok(())
+    }
+}
+
+type Records = BTreeMap<(String, &'static str), CachedRecord>;
+
+// One line per record, whose type follows from its target, so files written before records were kept
+// per type still parse
+fn parse(contents: &str) -> Records {
+    contents.lines()
+        .filter_map(parse_line)
+        .map(|(name, record)| ((name, record_type(&record.target)), record))
+        .collect()
+}
+
+fn serialize(records: &Records) -> String {
+    records.iter()
+        .map(|((name, _), record)| {
+            let secs = record.confirmed_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            format!("{}\t{}\t{}\n", name, record.target, secs)
+        })
+        .collect()
+}
+
+fn parse_line(line: &str) -> Option<(String, CachedRecord)> {
+    let mut fields = line.split('\t');
+    let name = fields.next()?.to_owned();
+    let target = fields.next()?.parse().ok()?;
+    let secs = fields.next()?.parse().ok()?;
+
+    Some((name, CachedRecord { target, confirmed_at: UNIX_EPOCH + Duration::from_secs(secs) }))
+}
@@ -0,0 +1,152 @@
+use std::fmt;
+
+use crate::api::{IpAddr, Service};
+use crate::validate;
+
+/// The result of one diagnostic check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Check {
+    Passed,
+    /// The check failed, with a description of what is wrong
+    Failed(String),
+    /// The check could not run, usually because an earlier check failed or its input was not given
+    Skipped(String)
+}
+
+impl Check {
+    /// Returns `true` unless the check failed
+    pub fn is_ok(&self) -> bool {
+        !matches!(self, Check::Failed(_))
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Check::Passed => write!(f, "ok"),
+            Check::Failed(reason) => write!(f, "failed: {}", reason),
+            Check::Skipped(reason) => write!(f, "skipped: {}", reason)
+        }
+    }
+}
+
+/// A report of what works and what does not for one subdomain, produced by `diagnose`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnosis {
+    /// Whether the subdomain name is one the API accepts
+    pub name: Check,
+    /// Whether any API endpoint answers HTTP requests
+    pub api: Check,
+    /// Whether the subdomain resolves through the system resolver
+    pub dns: Check,
+    /// Whether the API accepts the token for the subdomain
+    pub token: Check,
+    /// Whether the subdomain resolves to the expected public address
+    pub public_ip: Check
+}
+
+impl Diagnosis {
+    /// Returns `true` if no check failed
+    pub fn is_healthy(&self) -> bool {
+        [&self.name, &self.api, &self.dns, &self.token, &self.public_ip].iter().all(|check| check.is_ok())
+    }
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "name:      {}", self.name)?;
+        writeln!(f, "api:       {}", self.api)?;
+        writeln!(f, "dns:       {}", self.dns)?;
+        writeln!(f, "token:     {}", self.token)?;
+        write!(f, "public ip: {}", self.public_ip)
+    }
+}
+
+/// Checks the name, API connectivity, DNS resolution and target of a subdomain, without changing anything
+///
+/// The API has no endpoint for checking a token without using it, so the token check is always
+/// skipped here. Use `diagnose_with_token` to check it with a real update.
+///
+/// # Arguments
+///
+/// * `service` - The service whose endpoints and configuration are checked
+/// * `subdomain` - The subdomain to check
+/// * `public_ip` - The address the subdomain should point to, or `None` to skip the comparison
+///
+/// # Examples
+///
+/// ```no_run
+/// let service = anondns_api::api::Service::new();
+/// let diagnosis = anondns_api::diagnose::diagnose(&service, "example_subdomain", None);
+/// if !diagnosis.is_healthy() {
+///     eprintln!("{}", diagnosis);
+/// }
+/// ```
+pub fn diagnose(service: &Service, subdomain: &str, public_ip: Option<IpAddr>) -> Diagnosis {
+    check(service, subdomain, None, public_ip)
+}
+
+/// Runs the checks of `diagnose` and also checks the token by updating the record to `target`
+///
+/// This is not read-only: it sends a real update, which changes the record if it pointed elsewhere and
+/// is recorded in the service's cache, history and audit log like any other. Pass the address the
+/// record is meant to have, never one taken from DNS, which may be stale. If a `RecordCache` attached
+/// to the service already holds `target`, no request is sent and the token is assumed to be the one
+/// that was last accepted.
+///
+/// # Examples
+///
+/// ```no_run
+/// let service = anondns_api::api::Service::new();
+/// let target = std::net::Ipv4Addr::new(203, 0, 113, 1).into();
+/// let diagnosis = anondns_api::diagnose::diagnose_with_token(&service, "example_subdomain", "example_token", target);
+/// println!("{}", diagnosis);
+/// ```
+pub fn diagnose_with_token(service: &Service, subdomain: &str, token: &str, target: IpAddr) -> Diagnosis {
+    check(service, subdomain, Some((token, target)), Some(target))
+}
+
+fn check(service: &Service, subdomain: &str, token: Option<(&str, IpAddr)>, public_ip: Option<IpAddr>) -> Diagnosis {
+    let name = match validate::subdomain(subdomain) {
+        Ok(()) => Check::Passed,
+        Err(err) => Check::Failed(err.to_string())
+    };
+
+    let api = match service.ping() {
+        Ok(()) => Check::Passed,
+        Err(err) => Check::Failed(err.to_string())
+    };
+
+    let resolved = service.lookup(subdomain);
+    let dns = match &resolved {
+        Ok(addresses) if addresses.is_empty() => Check::Failed(String::from("the subdomain has no addresses")),
+        Ok(_) => Check::Passed,
+        Err(err) => Check::Failed(err.to_string())
+    };
+    let addresses = resolved.unwrap_or_default();
+
+    let token = match token {
+        None => Check::Skipped(String::from("checking the token needs an update, see diagnose_with_token")),
+        Some(_) if !api.is_ok() => Check::Skipped(String::from("the API is unreachable")),
+        Some((token, target)) => match service.update(subdomain, target, token) {
+            Ok(_) => Check::Passed,
+            Err(err) => Check::Failed(err.to_string())
+        }
+    };
+
+    let public_ip = match public_ip {
+        None => Check::Skipped(String::from("no public address given")),
+        Some(_) if addresses.is_empty() => Check::Skipped(String::from("the subdomain does not resolve")),
+        Some(expected) if addresses.contains(&expected) => Check::Passed,
+        Some(expected) => Check::Failed(format!("resolves to {}, not {}", join(&addresses), expected))
+    };
+
+    Diagnosis { name, api, dns, token, public_ip }
+}
+
+fn join(addresses: &[IpAddr]) -> String {
+    addresses.iter()
+        .map(|address| address.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
\ No newline at end of file
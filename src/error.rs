@@ -1,6 +1,8 @@
 use reqwest;
 use std::fmt;
 
+use crate::api::RequestId;
+
 // Shorthand
 pub type Error = DnsApiError;
 
@@ -9,7 +11,130 @@ pub enum DnsApiError {
     BadRequest((i32, String)),
     UnknownErrorCode((i32, String)), // On the off-chance that AnonDNS actually does return error codes other than 0 and 1...
     Reqwest(reqwest::Error),
-    AddressParse(std::net::AddrParseError)
+    AddressParse(std::net::AddrParseError),
+    Io(std::io::Error),
+    CircuitOpen(std::time::Duration),
+    InvalidSubdomain(String),
+    Json(serde_json::Error),
+    UnsupportedStateVersion(u32),
+    NoHealthyTarget,
+    InvalidConfig(String),
+    InvalidTarget(String),
+    UnexpectedResponse(String),
+    VerificationFailed(String),
+    ConditionFailed(String),
+    ServiceUnavailable(String),
+    Cancelled,
+    /// An error of a request that was sent, with the identifier it was sent with, see `inner`
+    Request(RequestId, Box<DnsApiError>)
+}
+
+impl DnsApiError {
+    /// Returns the error itself, or the error inside if it is a `DnsApiError::Request`
+    ///
+    /// Errors of requests that were sent carry the identifier of the request, so match on the inner
+    /// error to tell them apart.
+    pub fn inner(&self) -> &DnsApiError {
+        match self {
+            DnsApiError::Request(_, err) => err.inner(),
+            err => err
+        }
+    }
+
+    /// Consumes the error and returns it, or the error inside if it is a `DnsApiError::Request`
+    pub fn into_inner(self) -> DnsApiError {
+        match self {
+            DnsApiError::Request(_, err) => err.into_inner(),
+            err => err
+        }
+    }
+
+    /// Returns the identifier of the request that failed, if one was sent
+    pub fn request_id(&self) -> Option<&RequestId> {
+        match self {
+            DnsApiError::Request(id, _) => Some(id),
+            _ => None
+        }
+    }
+
+    // Attaches the identifier of the request the error happened in, unless it already has one
+    pub(crate) fn for_request(self, id: &RequestId) -> DnsApiError {
+        match self {
+            err @ DnsApiError::Request(..) => err,
+            err => DnsApiError::Request(id.clone(), Box::new(err))
+        }
+    }
+
+    /// Returns `true` if the error means the API could not be reached at all, rather than rejecting the request
+    pub fn is_connectivity(&self) -> bool {
+        match self.inner() {
+            DnsApiError::Reqwest(err) => err.is_connect() || err.is_timeout() || err.status().is_some_and(|status| status.is_server_error()),
+            DnsApiError::ServiceUnavailable(_) => true,
+            _ => false
+        }
+    }
+
+    /// Returns `true` if the same request is worth sending again later unchanged
+    ///
+    /// That covers connectivity failures, rate limiting by the server, and calls held back by an
+    /// open circuit breaker.
+    pub fn is_retryable(&self) -> bool {
+        match self.inner() {
+            DnsApiError::Reqwest(err) if err.status() == Some(reqwest::StatusCode::TOO_MANY_REQUESTS) => true,
+            DnsApiError::CircuitOpen(_) => true,
+            err => err.is_connectivity()
+        }
+    }
+
+    /// Returns the process exit code a command-line tool should use for the error
+    ///
+    /// Codes are 2 for requests rejected as invalid, locally or by the server, 3 for a token the server
+    /// refused, 4 for network failures, 5 for rate limiting, and 1 for anything else. Success is 0.
+    pub fn exit_code(&self) -> i32 {
+        match self.inner() {
+            DnsApiError::BadRequest((_, msg)) if msg.to_lowercase().contains("token") => 3,
+            DnsApiError::BadRequest(_) | DnsApiError::InvalidSubdomain(_) | DnsApiError::InvalidTarget(_) | DnsApiError::InvalidConfig(_) => 2,
+            DnsApiError::Reqwest(err) if err.status() == Some(reqwest::StatusCode::TOO_MANY_REQUESTS) => 5,
+            DnsApiError::CircuitOpen(_) => 4,
+            err if err.is_connectivity() => 4,
+            _ => 1
+        }
+    }
+
+    /// Returns a short explanation of the error and how to fix it, suitable for showing to a user
+    pub fn help(&self) -> &'static str {
+        match self {
+            DnsApiError::BadRequest((_, msg)) => {
+                let msg = msg.to_lowercase();
+                if msg.contains("token") {
+                    "the token does not belong to this subdomain; use the token returned when it was registered"
+                } else if msg.contains("exist") || msg.contains("taken") || msg.contains("registered") {
+                    "this name is already registered; use update() with its token, or pick another name"
+                } else {
+                    "the server rejected the request; check the subdomain, token and target"
+                }
+            },
+            DnsApiError::UnknownErrorCode(_) => "the server answered with an error this crate does not know; check for a newer version of the crate",
+            DnsApiError::Reqwest(err) if err.is_timeout() => "the server did not answer in time; retry later or raise the timeout",
+            DnsApiError::Reqwest(err) if err.is_connect() => "the server could not be reached; check the network connection, proxy and DNS",
+            DnsApiError::Reqwest(_) => "the HTTP request failed; retry later, and check the endpoint if it keeps failing",
+            DnsApiError::AddressParse(_) => "an address could not be parsed; the server may have answered with something other than an IP address",
+            DnsApiError::Io(_) => "a local file or network operation failed; check paths and permissions",
+            DnsApiError::CircuitOpen(_) => "recent calls kept failing, so calls are paused; wait for the cooldown to pass",
+            DnsApiError::InvalidSubdomain(_) => "use letters, digits, hyphens and underscores only, without the parent zone, and avoid reserved names",
+            DnsApiError::Json(_) => "a file or response was not valid JSON; check that it was written by this crate",
+            DnsApiError::UnsupportedStateVersion(_) => "the state file was written by a newer version of this crate; upgrade to read it",
+            DnsApiError::NoHealthyTarget => "every target failed its health check; check the services behind the record",
+            DnsApiError::InvalidConfig(_) => "the service configuration is inconsistent; see the error message for the setting involved",
+            DnsApiError::InvalidTarget(_) => "use a public address, or allow private targets on the service builder for split-horizon setups",
+            DnsApiError::UnexpectedResponse(_) => "the API answered in an unexpected way and may have changed; check for a newer version of the crate",
+            DnsApiError::VerificationFailed(_) => "DNS may still serve the previous address; check again once cached answers expire",
+            DnsApiError::ConditionFailed(_) => "the record no longer points where expected, so another updater may have changed it; look it up before retrying",
+            DnsApiError::ServiceUnavailable(_) => "something other than the API answered, such as a captive portal, bot challenge or maintenance page; sign in to the network or retry later",
+            DnsApiError::Cancelled => "the batch was cancelled before this record was sent; run the remaining records again",
+            DnsApiError::Request(_, err) => err.help()
+        }
+    }
 }
 
 impl fmt::Display for DnsApiError {
@@ -17,8 +142,22 @@ impl fmt::Display for DnsApiError {
         match self {
             DnsApiError::BadRequest((code, msg)) => write!(f, "anondns server received a bad request (code {}): {}", code, msg),
             DnsApiError::UnknownErrorCode((code, msg)) => write!(f, "anondns server responded with an unknown error (code {}): {}", code, msg),
-            DnsApiError::Reqwest(err) => write!(f, "error in library 'reqwest': {}", err.to_string()),
-            DnsApiError::AddressParse(err) => write!(f, "error when parsing address: {}", err.to_string())
+            DnsApiError::Reqwest(err) => write!(f, "error in library 'reqwest': {}", err),
+            DnsApiError::AddressParse(err) => write!(f, "error when parsing address: {}", err),
+            DnsApiError::Io(err) => write!(f, "i/o error: {}", err),
+            DnsApiError::CircuitOpen(remaining) => write!(f, "circuit breaker is open, calls are rejected for another {:?}", remaining),
+            DnsApiError::InvalidSubdomain(reason) => write!(f, "invalid subdomain: {}", reason),
+            DnsApiError::Json(err) => write!(f, "error in library 'serde_json': {}", err),
+            DnsApiError::UnsupportedStateVersion(version) => write!(f, "state file version {} is newer than this crate supports", version),
+            DnsApiError::NoHealthyTarget => write!(f, "none of the record's targets passed its health check"),
+            DnsApiError::InvalidConfig(reason) => write!(f, "invalid configuration: {}", reason),
+            DnsApiError::InvalidTarget(reason) => write!(f, "invalid target: {}", reason),
+            DnsApiError::UnexpectedResponse(reason) => write!(f, "unexpected response: {}", reason),
+            DnsApiError::VerificationFailed(reason) => write!(f, "verification failed: {}", reason),
+            DnsApiError::ConditionFailed(reason) => write!(f, "condition failed: {}", reason),
+            DnsApiError::ServiceUnavailable(snippet) => write!(f, "the api is unavailable, got a non-json page: {}", snippet),
+            DnsApiError::Cancelled => write!(f, "cancelled before the record was sent"),
+            DnsApiError::Request(id, err) => write!(f, "{} (request {})", err, id)
         }
     }
 }
@@ -33,4 +172,16 @@ impl From<std::net::AddrParseError> for DnsApiError {
     fn from(err: std::net::AddrParseError) -> DnsApiError {
         DnsApiError::AddressParse(err)
     }
+}
+
+impl From<std::io::Error> for DnsApiError {
+    fn from(err: std::io::Error) -> DnsApiError {
+        DnsApiError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for DnsApiError {
+    fn from(err: serde_json::Error) -> DnsApiError {
+        DnsApiError::Json(err)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,89 @@
+use crate::api::{AuthorizedRecord, IpAddr, RegisterOutcome, Subdomain};
+use crate::error;
+use crate::resolver::Resolver;
+use crate::state::State;
+
+/// Chains the common steps after registering a subdomain through `Subdomain::register`
+///
+/// # Examples
+///
+/// ```no_run
+/// use anondns_api::ext::{RecordResultExt, RegisterResultExt};
+///
+/// let service = anondns_api::api::Service::new();
+/// let mut state = anondns_api::state::load_state("state.json").unwrap_or_default();
+/// let subdomain = service.subdomain("example_subdomain");
+/// let target = std::net::Ipv4Addr::new(203, 0, 113, 1);
+///
+/// // Registers the name, or updates it with the stored token if it was registered before
+/// let record = subdomain.register(target)
+///     .store_token(&mut state)
+///     .or_update_with(&subdomain, target, &state)
+///     .and_verify(target)
+///     .unwrap();
+/// ```
+pub trait RegisterResultExt<'a>: Sized {
+    /// Stores the token of a successful registration in `state`, leaving the result unchanged
+    fn store_token(self, state: &mut State) -> Self;
+
+    /// Drops the outcome of a successful registration, or if the server rejected it, updates the record
+    /// to `target` using the token stored for it in `state`
+    ///
+    /// The original error is returned if registering failed for another reason or no token is stored.
+    fn or_update_with(self, subdomain: &Subdomain<'a>, target: impl Into<IpAddr>, state: &State) -> Result<AuthorizedRecord<'a>, error::DnsApiError>;
+}
+
+impl<'a> RegisterResultExt<'a> for Result<(AuthorizedRecord<'a>, RegisterOutcome), error::DnsApiError> {
+    fn store_token(self, state: &mut State) -> Self {
+        if let Ok((record, _)) = &self {
+            state.set_token(record.name(), record.token());
+        }
+        self
+    }
+
+    fn or_update_with(self, subdomain: &Subdomain<'a>, target: impl Into<IpAddr>, state: &State) -> Result<AuthorizedRecord<'a>, error::DnsApiError> {
+        let err = match self {
+            Ok((record, _)) => return Ok(record),
+            Err(err) if matches!(err.inner(), error::DnsApiError::BadRequest(_)) => err,
+            Err(err) => return Err(err)
+        };
+
+        let Some(token) = state.record(subdomain.name()).and_then(|record| record.token.clone()) else {
+            return Err(err);
+        };
+        let record = subdomain.authorize(token);
+        record.update(target)?;
+
+        Ok(record)
+    }
+}
+
+/// Chains steps after obtaining an authorized record
+pub trait RecordResultExt: Sized {
+    /// Checks that the record resolves to `target`, failing with `DnsApiError::VerificationFailed` if not
+    ///
+    /// DNS caches may serve the previous address for a while after a change, so this is most useful
+    /// after a delay or for records that did not change.
+    fn and_verify(self, target: impl Into<IpAddr>) -> Self;
+
+    /// Like `and_verify`, but resolves the record through the given resolver
+    fn and_verify_with(self, target: impl Into<IpAddr>, resolver: &Resolver) -> Self;
+}
+
+impl RecordResultExt for Result<AuthorizedRecord<'_>, error::DnsApiError> {
+    fn and_verify(self, target: impl Into<IpAddr>) -> Self {
+        self.and_verify_with(target, &Resolver::System)
+    }
+
+    fn and_verify_with(self, target: impl Into<IpAddr>, resolver: &Resolver) -> Self {
+        let record = self?;
+        let target = target.into();
+
+        let addresses = record.lookup_with(resolver)?;
+        if !addresses.contains(&target) {
+            return Err(error::DnsApiError::VerificationFailed(format!("{} does not resolve to {} yet", record.name(), target)));
+        }
+
+        Ok(record)
+    }
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use crate::error;
+
+/// Pings a healthchecks.io (or compatible) check after each update cycle
+///
+/// A successful cycle pings the check URL itself and a failed one pings its `/fail` endpoint, so the
+/// monitoring service alerts when pings stop arriving or report failure.
+///
+/// # Examples
+///
+/// ```no_run
+/// let service = anondns_api::api::Service::new();
+/// let check = anondns_api::healthcheck::Healthcheck::new("https://hc-ping.com/your-uuid");
+///
+/// let result = service.update("example_subdomain", std::net::Ipv4Addr::new(203, 0, 113, 1), "example_token");
+/// let _ = check.report(&result);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Healthcheck {
+    client: reqwest::blocking::Client,
+    url: String
+}
+
+impl Healthcheck {
+    /// Creates a pinger for the check at `url`, with a short timeout so a slow monitor never stalls updates
+    pub fn new(url: &str) -> Self {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("failed to initialize reqwest blocking client");
+
+        Healthcheck::with_client(url, client)
+    }
+
+    /// Creates a pinger that sends its requests through an existing reqwest client
+    pub fn with_client(url: &str, client: reqwest::blocking::Client) -> Self {
+        Healthcheck {
+            client,
+            url: url.trim_end_matches('/').to_owned()
+        }
+    }
+
+    /// Reports a successful cycle
+    pub fn success(&self) -> Result<(), error::DnsApiError> {
+        self.ping(&self.url, None)
+    }
+
+    /// Reports a failed cycle, attaching `message` as the request body so it shows up in the check's log
+    pub fn fail(&self, message: &str) -> Result<(), error::DnsApiError> {
+        self.ping(&format!("{}/fail", self.url), Some(message))
+    }
+
+    /// Reports the outcome of a cycle, pinging `/fail` with the error message if it failed
+    pub fn report<T>(&self, result: &Result<T, error::DnsApiError>) -> Result<(), error::DnsApiError> {
+        match result {
+            Ok(_) => self.success(),
+            Err(err) => self.fail(&err.to_string())
+        }
+    }
+
+    fn ping(&self, url: &str, body: Option<&str>) -> Result<(), error::DnsApiError> {
+        let request = match body {
+            Some(body) => self.client.post(url).body(body.to_owned()),
+            None => self.client.get(url)
+        };
+        request.send()?.error_for_status()?;
+
+        Ok(())
+    }
+}
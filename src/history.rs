@@ -0,0 +1,87 @@
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::api::IpAddr;
+use crate::lock;
+
+/// The kind of change a `HistoryEntry` records
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Register,
+    Update
+}
+
+/// A single change made to a subdomain
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub at: SystemTime,
+    pub subdomain: String,
+    pub operation: Operation,
+    /// The target before the change, when it was known
+    pub old: Option<IpAddr>,
+    pub new: IpAddr
+}
+
+/// An append-only on-disk log of the changes made to each subdomain
+///
+/// When attached to a `Service`, every successful register and update is appended here. Entries are
+/// never rewritten, so the file grows by one line per change. Appends take an exclusive lock on the file,
+/// so several processes can share one history.
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf
+}
+
+impl History {
+    /// Uses the history stored at `path`, which is created on the first append
+    pub fn open<P: Into<PathBuf>>(path: P) -> Self {
+        History { path: path.into() }
+    }
+
+    /// Appends an entry to the history
+    pub fn append(&self, entry: &HistoryEntry) -> io::Result<()> {
+        let secs = entry.at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let operation = match entry.operation {
+            Operation::Register => "register",
+            Operation::Update => "update"
+        };
+        let old = entry.old.map_or_else(|| String::from("-"), |old| old.to_string());
+
+        lock::append(&self.path, &format!("{}\t{}\t{}\t{}\t{}\n", secs, entry.subdomain, operation, old, entry.new))
+    }
+
+    /// Returns every recorded entry, oldest first
+    pub fn entries(&self) -> io::Result<Vec<HistoryEntry>> {
+        let contents = lock::read(&self.path)?.unwrap_or_default();
+
+        Ok(contents.lines().filter_map(parse_line).collect())
+    }
+
+    /// Returns the recorded entries of a single subdomain, oldest first
+    pub fn entries_for(&self, subdomain: &str) -> io::Result<Vec<HistoryEntry>> {
+        let mut entries = self.entries()?;
+        entries.retain(|entry| entry.subdomain == subdomain);
+
+        Ok(entries)
+    }
+}
+
+fn parse_line(line: &str) -> Option<HistoryEntry> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let [secs, subdomain, operation, old, new] = fields.as_slice() else {
+        return None;
+    };
+
+    Some(HistoryEntry {
+        at: UNIX_EPOCH + Duration::from_secs(secs.parse().ok()?),
+        subdomain: subdomain.to_string(),
+        operation: match *operation {
+            "register" => Operation::Register,
+            "update" => Operation::Update,
+            _ => return None
+        },
+        old: old.parse().ok(),
+        new: new.parse().ok()?
+    })
+}
@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use crate::api::{AddressFamily, IpAddr, Ipv4Addr, Ipv6Addr};
+use crate::error;
+
+/// Services answering a plain-text IPv4 address, tried in order
+pub const IPV4_SOURCES: &[&str] = &["https://api.ipify.org", "https://ipv4.icanhazip.com"];
+
+/// Services answering a plain-text IPv6 address, tried in order
+pub const IPV6_SOURCES: &[&str] = &["https://api6.ipify.org", "https://ipv6.icanhazip.com"];
+
+const NAT_PMP_PORT: u16 = 5351;
+
+/// Detects the public address of this host by asking well-known "what is my IP" services
+///
+/// `AddressFamily::Any` detects the IPv4 address. The services are tried in order until one answers
+/// with an address of the requested family.
+///
+/// # Errors
+///
+/// Returns the error of the last service tried, usually `DnsApiError::Reqwest(reqwest::Error)` or
+/// `DnsApiError::AddressParse(std::net::AddrParseError)`.
+pub fn public_ip(family: AddressFamily) -> Result<IpAddr, error::DnsApiError> {
+    detect(None, family)
+}
+
+// Detects the address connections bound to `local_address`, if any, are seen from
+pub(crate) fn detect(local_address: Option<IpAddr>, family: AddressFamily) -> Result<IpAddr, error::DnsApiError> {
+    let v6 = match (local_address, family) {
+        (Some(address), _) => address.is_ipv6(),
+        (None, family) => family == AddressFamily::V6
+    };
+    let bind = local_address.unwrap_or(if v6 { Ipv6Addr::UNSPECIFIED.into() } else { Ipv4Addr::UNSPECIFIED.into() });
+
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .local_address(bind)
+        .build()?;
+
+    detect_from(&client, if v6 { IPV6_SOURCES } else { IPV4_SOURCES }, v6)
+}
+
+pub(crate) fn detect_from(client: &reqwest::blocking::Client, sources: &[&str], v6: bool) -> Result<IpAddr, error::DnsApiError> {
+    let mut last = None;
+
+    for source in sources {
+        let result = client.get(*source)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.text())
+            .map_err(error::DnsApiError::from)
+            .and_then(|body| Ok(body.trim().parse::<IpAddr>()?));
+
+        match result {
+            Ok(address) if address.is_ipv6() == v6 => return Ok(address),
+            Ok(address) => last = Some(error::DnsApiError::UnexpectedResponse(format!("{} answered with {}", source, address))),
+            Err(err) => last = Some(err)
+        }
+    }
+
+    Err(last.unwrap_or_else(|| error::DnsApiError::InvalidConfig(String::from("no address sources configured"))))
+}
+
+
+/// Asks the router at `gateway` for its external IPv4 address using NAT-PMP
+///
+/// This avoids third-party services entirely, but only works with routers that have NAT-PMP (or PCP,
+/// which answers NAT-PMP requests) enabled. The gateway is usually the default route of the local
+/// network, such as `192.168.1.1`.
+///
+/// # Errors
+///
+/// Returns `DnsApiError::Io(std::io::Error)` if the router does not answer within `timeout`, or
+/// `DnsApiError::UnexpectedResponse(String)` if it answers with an error or a malformed response.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// let address = anondns_api::ip::router_ip(std::net::Ipv4Addr::new(192, 168, 1, 1), Duration::from_secs(2)).unwrap();
+/// ```
+pub fn router_ip(gateway: Ipv4Addr, timeout: Duration) -> Result<IpAddr, error::DnsApiError> {
+    router_ip_at((gateway, NAT_PMP_PORT).into(), timeout)
+}
+
+pub(crate) fn router_ip_at(gateway: std::net::SocketAddr, timeout: Duration) -> Result<IpAddr, error::DnsApiError> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(gateway)?;
+
+    // Version 0, opcode 0 asks for the external address
+    socket.send(&[0, 0])?;
+    let mut response = [0; 16];
+    let len = socket.recv(&mut response)?;
+
+    // Version, opcode 128, a 2-byte result code, 4 bytes of uptime and the address
+    if len < 12 || response[0] != 0 || response[1] != 128 {
+        return Err(error::DnsApiError::UnexpectedResponse(String::from("malformed NAT-PMP response")));
+    }
+    let code = u16::from_be_bytes([response[2], response[3]]);
+    if code != 0 {
+        return Err(error::DnsApiError::UnexpectedResponse(format!("router answered NAT-PMP result code {}", code)));
+    }
+
+    Ok(Ipv4Addr::new(response[8], response[9], response[10], response[11]).into())
+}
\ No newline at end of file
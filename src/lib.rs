@@ -1,22 +1,155 @@
 pub mod api;
+pub mod audit;
+pub mod breaker;
+pub mod bulk;
+pub mod cache;
+pub mod diagnose;
 pub mod error;
+pub mod ext;
+#[cfg(feature = "healthcheck")]
+pub mod healthcheck;
+pub mod history;
+pub mod ip;
+pub mod lock;
+pub mod middleware;
+pub mod migrate;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "notify")]
+pub mod notify;
+pub mod offline;
+pub mod prelude;
+pub mod probe;
+pub mod protocol;
+pub mod queue;
+pub mod resolver;
+pub mod state;
+pub mod sync;
+pub mod validate;
+pub mod watch;
+
+use std::sync::OnceLock;
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("enable the `native-tls` or the `rustls` feature, the API is only served over HTTPS");
+
+// Shared by the free functions, so quick scripts reuse one connection pool
+static DEFAULT_SERVICE: OnceLock<api::Service> = OnceLock::new();
+
+fn default_service() -> &'static api::Service {
+    DEFAULT_SERVICE.get_or_init(api::Service::new)
+}
+
+/// Sets the service used by the free functions of this crate, which is `Service::new()` unless set
+///
+/// Call this once, early in `main`, before any free function runs.
+///
+/// # Errors
+///
+/// Returns `DnsApiError::InvalidConfig(String)` if the default service was already initialized, either
+/// by an earlier call or by a free function.
+///
+/// # Examples
+///
+/// ```no_run
+/// let service = anondns_api::api::Service::builder()
+///     .timeout(std::time::Duration::from_secs(5))
+///     .build()
+///     .unwrap();
+/// anondns_api::init(service).unwrap();
+///
+/// let token = anondns_api::register("example_subdomain", std::net::Ipv4Addr::new(203, 0, 113, 1)).unwrap().token;
+/// ```
+pub fn init(service: api::Service) -> Result<(), error::DnsApiError> {
+    DEFAULT_SERVICE.set(service)
+        .map_err(|_| error::DnsApiError::InvalidConfig(String::from("the default service is already initialized")))
+}
+
+/// Registers a subdomain using the default `Service`, see `api::Service::register`
+///
+/// # Examples
+///
+/// ```no_run
+/// let token = anondns_api::register("example_subdomain", std::net::Ipv4Addr::new(203, 0, 113, 1)).unwrap().token;
+/// anondns_api::update("example_subdomain", std::net::Ipv4Addr::new(203, 0, 113, 2), &token).unwrap();
+/// ```
+pub fn register(subdomain: &str, target: impl Into<api::IpAddr>) -> Result<api::RegisterOutcome, error::DnsApiError> {
+    default_service().register(subdomain, target)
+}
+
+/// Updates a subdomain using the default `Service`, see `api::Service::update`
+pub fn update(subdomain: &str, target: impl Into<api::IpAddr>, token: impl AsRef<str>) -> Result<api::UpdateOutcome, error::DnsApiError> {
+    default_service().update(subdomain, target, token)
+}
+
+/// Resolves a subdomain using the default `Service`, see `api::Service::lookup`
+pub fn lookup(subdomain: &str) -> Result<Vec<api::IpAddr>, error::DnsApiError> {
+    default_service().lookup(subdomain)
+}
 
 #[cfg(test)]
 mod tests {
+    // Serves a single HTTP response with the given JSON body on a local port and returns its base URL
+    fn serve_once(body: &'static str) -> String {
+        serve_capture(body).0
+    }
+
+    // Like `serve_once`, but also returns a receiver for the raw request the server received
+    fn serve_capture(body: &'static str) -> (String, std::sync::mpsc::Receiver<String>) {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 4096];
+            let len = stream.read(&mut request).unwrap_or(0);
+            let _ = sender.send(String::from_utf8_lossy(&request[..len]).into_owned());
+
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+
+        (url, receiver)
+    }
+
+    // Serves the given JSON bodies, one per connection in order, on a local port and returns its base URL
+    fn serve_sequence(bodies: &'static [&'static str]) -> String {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        std::thread::spawn(move || {
+            for body in bodies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request);
+
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        url
+    }
+
     #[test]
     fn get() {
         let body = reqwest::blocking::get("https://anondns.net").unwrap()
             .text().unwrap();
 
-        assert_eq!(body.len() > 1, true, "GET request body was empty");
+        assert!(body.len() > 1, "GET request body was empty");
     }
 
     #[test]
     fn register() -> Result<(), crate::error::DnsApiError> {
         const TEST_SUBDOMAIN: &str = "INSERT_NAME_HERE";
-        const TEST_TARGET: std::net::Ipv4Addr = std::net::Ipv4Addr::new(127, 0, 0, 1);
+        const TEST_TARGET: std::net::Ipv4Addr = std::net::Ipv4Addr::new(203, 0, 113, 1);
 
-        let mut service = crate::api::Service::new();
+        let service = crate::api::Service::new();
         let result = service.register(TEST_SUBDOMAIN, TEST_TARGET);
 
         result.map(|_| ())
@@ -25,12 +158,1466 @@ mod tests {
     #[test]
     fn update() -> Result<(), crate::error::DnsApiError> {
         const TEST_SUBDOMAIN: &str = "INSERT_NAME_HERE";
-        const TEST_UPDATE_TARGET: std::net::Ipv4Addr = std::net::Ipv4Addr::new(255, 255, 255, 255);
+        const TEST_UPDATE_TARGET: std::net::Ipv4Addr = std::net::Ipv4Addr::new(203, 0, 113, 2);
         const TEST_TOKEN: &str = "INSERT_TOKEN_HERE";
 
-        let mut service = crate::api::Service::new();
+        let service = crate::api::Service::new();
         let result = service.update(TEST_SUBDOMAIN, TEST_UPDATE_TARGET, String::from(TEST_TOKEN));
 
         result.map(|_| ())
     }
+
+    #[test]
+    fn queue_coalesces() {
+        const TEST_SUBDOMAIN: &str = "INSERT_NAME_HERE";
+
+        let mut queue = crate::queue::UpdateQueue::new(std::time::Duration::from_secs(30));
+        queue.push(TEST_SUBDOMAIN, std::net::Ipv4Addr::new(127, 0, 0, 1), String::from("token"));
+        queue.push(TEST_SUBDOMAIN, std::net::Ipv4Addr::new(127, 0, 0, 2), String::from("token"));
+        queue.push("other", std::net::Ipv4Addr::new(127, 0, 0, 3), String::from("token"));
+
+        assert_eq!(queue.len(), 2, "updates for the same subdomain were not coalesced");
+    }
+
+    #[test]
+    fn offline_queue_persists() -> std::io::Result<()> {
+        let path = std::env::temp_dir().join("anondns-api-offline-queue-test");
+        let _ = std::fs::remove_file(&path);
+
+        let operation = crate::offline::Operation::Update {
+            subdomain: String::from("INSERT_NAME_HERE"),
+            target: std::net::Ipv4Addr::new(127, 0, 0, 1).into(),
+            token: String::from("INSERT_TOKEN_HERE")
+        };
+
+        let mut queue = crate::offline::OfflineQueue::open(&path)?;
+        queue.push(operation.clone())?;
+
+        let reopened = crate::offline::OfflineQueue::open(&path)?;
+        assert_eq!(reopened.pending().collect::<Vec<_>>(), vec![&operation], "queued operation was not persisted");
+
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn breaker_opens() {
+        let mut breaker = crate::breaker::CircuitBreaker::new(2, std::time::Duration::from_secs(60));
+        let call = breaker.check().unwrap();
+        breaker.record_failure(call);
+        let call = breaker.check().unwrap();
+        breaker.record_success(call);
+        let call = breaker.check().unwrap();
+        breaker.record_failure(call);
+        assert_eq!(breaker.state(), crate::breaker::State::Closed, "failures were counted across a success");
+
+        let late = breaker.check().unwrap();
+        let call = breaker.check().unwrap();
+        breaker.record_failure(call);
+        assert_eq!(breaker.state(), crate::breaker::State::Open);
+        assert!(breaker.check().is_err(), "open breaker let a call through");
+
+        breaker.record_success(late);
+        assert_eq!(breaker.state(), crate::breaker::State::Open, "a call from before the breaker opened closed it");
+    }
+
+    #[test]
+    fn mirror_failover() -> Result<(), crate::error::DnsApiError> {
+        const TEST_SUBDOMAIN: &str = "INSERT_NAME_HERE";
+        const TEST_TARGET: std::net::Ipv4Addr = std::net::Ipv4Addr::new(127, 0, 0, 1);
+
+        let mirror = serve_once(r#"{"code": 0, "data": "ok", "token": "0123456789abcdef0123456789abcdef"}"#);
+        let service = crate::api::Service::builder()
+            .allow_private_targets(true)
+            .endpoint("http://127.0.0.1:1")
+            .mirror(&mirror)
+            .build()?;
+
+        let outcome = service.register(TEST_SUBDOMAIN, TEST_TARGET)?;
+        assert_eq!(outcome.token, "0123456789abcdef0123456789abcdef");
+
+        Ok(())
+    }
+
+    #[test]
+    fn cache_skips_noop_update() -> Result<(), crate::error::DnsApiError> {
+        const TEST_SUBDOMAIN: &str = "INSERT_NAME_HERE";
+        const TEST_TARGET: std::net::Ipv4Addr = std::net::Ipv4Addr::new(127, 0, 0, 1);
+
+        let path = std::env::temp_dir().join("anondns-api-cache-test");
+        let _ = std::fs::remove_file(&path);
+
+        let mut cache = crate::cache::RecordCache::open(&path)?;
+        cache.insert(TEST_SUBDOMAIN, TEST_TARGET.into())?;
+
+        // Nothing listens on the endpoint, so the update only succeeds if it is skipped
+        let service = crate::api::Service::builder()
+            .allow_private_targets(true)
+            .endpoint("http://127.0.0.1:1")
+            .cache(crate::cache::RecordCache::open(&path)?)
+            .build()?;
+
+        let outcome = service.update(TEST_SUBDOMAIN, TEST_TARGET, String::from("INSERT_TOKEN_HERE"))?;
+        assert_eq!(outcome.target, TEST_TARGET);
+        assert_eq!(outcome.previous, Some(TEST_TARGET.into()));
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn service_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<crate::api::Service>();
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn parse_timestamp() {
+        let expected = chrono::DateTime::from_timestamp(1700000000, 0);
+
+        assert_eq!(crate::api::parse_timestamp("2023-11-14T22:13:20Z"), expected);
+        assert_eq!(crate::api::parse_timestamp("2023-11-14 22:13:20"), expected);
+        assert_eq!(crate::api::parse_timestamp("1700000000"), expected);
+        assert_eq!(crate::api::parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    fn validate_subdomain() {
+        assert!(crate::validate::subdomain("INSERT_NAME_HERE").is_ok());
+        assert!(crate::validate::subdomain("*.myname").is_ok());
+        assert!(crate::validate::subdomain("*").is_err(), "bare wildcard was accepted");
+        assert!(crate::validate::subdomain("my.*.name").is_err(), "inner wildcard was accepted");
+        assert!(crate::validate::subdomain("name/../set").is_err(), "path characters were accepted");
+        assert!(crate::validate::subdomain("").is_err(), "empty name was accepted");
+    }
+
+    #[test]
+    fn history_round_trip() -> std::io::Result<()> {
+        let path = std::env::temp_dir().join("anondns-api-history-test");
+        let _ = std::fs::remove_file(&path);
+
+        let history = crate::history::History::open(&path);
+        let entry = crate::history::HistoryEntry {
+            at: std::time::UNIX_EPOCH + std::time::Duration::from_secs(1700000000),
+            subdomain: String::from("INSERT_NAME_HERE"),
+            operation: crate::history::Operation::Update,
+            old: Some(std::net::Ipv4Addr::new(127, 0, 0, 1).into()),
+            new: std::net::Ipv4Addr::new(127, 0, 0, 2).into()
+        };
+        history.append(&entry)?;
+
+        assert_eq!(history.entries_for("INSERT_NAME_HERE")?, vec![entry]);
+        assert!(history.entries_for("other")?.is_empty());
+
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn plan_display() {
+        use crate::sync::{Change, DesiredRecord, Plan, PlannedRecord};
+
+        let plan = Plan {
+            records: vec![
+                PlannedRecord { record: DesiredRecord::new("new", std::net::Ipv4Addr::new(127, 0, 0, 1)), change: Change::Register },
+                PlannedRecord {
+                    record: DesiredRecord::new("drifted", std::net::Ipv4Addr::new(127, 0, 0, 2)).with_token("INSERT_TOKEN_HERE"),
+                    change: Change::Update { live: vec![std::net::Ipv4Addr::new(127, 0, 0, 3).into()] }
+                }
+            ]
+        };
+
+        assert!(plan.has_changes());
+        assert_eq!(plan.to_string(), "+ new -> 127.0.0.1\n~ drifted: 127.0.0.3 -> 127.0.0.2\n");
+    }
+
+    #[test]
+    fn state_round_trip() -> Result<(), crate::error::DnsApiError> {
+        let path = std::env::temp_dir().join("anondns-api-state-test.json");
+
+        let mut state = crate::state::State::new();
+        state.set_token("INSERT_NAME_HERE", "INSERT_TOKEN_HERE");
+        crate::state::save_state(&path, &state)?;
+
+        let loaded = crate::state::load_state(&path)?;
+        assert_eq!(loaded, state);
+        assert_eq!(loaded.record("INSERT_NAME_HERE").and_then(|record| record.token.as_deref()), Some("INSERT_TOKEN_HERE"));
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn instance_lock_is_exclusive() -> std::io::Result<()> {
+        let path = std::env::temp_dir().join("anondns-api-instance-lock-test");
+
+        let guard = crate::lock::InstanceLock::acquire(&path)?;
+        let second = crate::lock::InstanceLock::acquire(&path);
+        assert_eq!(second.map(|_| ()).unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+        assert_eq!(crate::lock::InstanceLock::holder(&path)?, Some(std::process::id()));
+
+        drop(guard);
+        crate::lock::InstanceLock::acquire(&path)?;
+
+        std::fs::remove_file(&path)
+    }
+
+    #[cfg(feature = "notify")]
+    #[test]
+    fn notifier_reports_failures_once() {
+        let webhook = serve_once("{}");
+        let notifier = crate::notify::Notifier::new()
+            .channel(crate::notify::Channel::Webhook(webhook))
+            .failure_threshold(2);
+        let failure = || Err(crate::error::DnsApiError::CircuitOpen(std::time::Duration::from_secs(1)));
+
+        assert!(notifier.observe("INSERT_NAME_HERE", &failure()).is_empty(), "notified before reaching the threshold");
+
+        let sent = notifier.observe("INSERT_NAME_HERE", &failure());
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].is_ok(), "webhook delivery failed");
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn mqtt_publish_packet() {
+        let packet = crate::mqtt::publish_packet("a/b", b"{}", true);
+        assert_eq!(packet, vec![0x31, 7, 0, 3, b'a', b'/', b'b', b'{', b'}']);
+
+        let long = crate::mqtt::publish_packet("t", &[0; 200], false);
+        assert_eq!(&long[..3], &[0x30, 0xCB, 0x01], "remaining length was not encoded as a varint");
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn mqtt_discovery_messages() {
+        let publisher = crate::mqtt::MqttPublisher::new("127.0.0.1:1883", "anondns/events").discovery_prefix("ha/");
+        let messages = publisher.discovery_messages("my-home");
+
+        let topics: Vec<&str> = messages.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(topics, ["ha/sensor/anondns_my-home_address/config", "ha/sensor/anondns_my-home_changed/config"]);
+        assert_eq!(messages[0].1["state_topic"], "anondns/events");
+        assert_eq!(
+            messages[0].1["value_template"],
+            "{% if value_json.event == 'changed' and value_json.subdomain == 'my-home' %}{{ value_json.new }}{% else %}{{ this.state }}{% endif %}"
+        );
+        assert!(publisher.publish_discovery("bad'name").is_err());
+        assert_eq!(messages[0].1["device"]["name"], "my-home.anondns.net");
+
+        let publisher = publisher.zone("dyn.example.org");
+        assert_eq!(publisher.discovery_messages("my-home")[0].1["device"]["name"], "my-home.dyn.example.org");
+
+        let wildcard = publisher.discovery_messages("*.blog.home");
+        assert_eq!(wildcard[0].0, "ha/sensor/anondns___blog_home_address/config");
+        assert_eq!(wildcard[0].1["unique_id"], "anondns___blog_home_address");
+        assert_eq!(wildcard[0].1["device"]["name"], "*.blog.home.dyn.example.org");
+        assert_ne!(publisher.discovery_messages("my_home")[0].1["unique_id"], messages[0].1["unique_id"], "my-home and my_home share an id");
+    }
+
+    #[test]
+    fn tcp_probe() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let probe = crate::probe::Probe::Tcp { port };
+        let timeout = std::time::Duration::from_secs(1);
+
+        assert!(probe.is_healthy(std::net::Ipv4Addr::new(127, 0, 0, 1).into(), timeout));
+        drop(listener);
+        assert!(!probe.is_healthy(std::net::Ipv4Addr::new(127, 0, 0, 1).into(), timeout), "closed port passed the probe");
+    }
+
+    #[test]
+    fn dead_targets_are_not_published() -> Result<(), crate::error::DnsApiError> {
+        // Nothing listens on the endpoint either, so the update only fails as expected if nothing was sent
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint("http://127.0.0.1:1").build()?;
+        let probe = crate::probe::Probe::Tcp { port: 1 };
+        let timeout = std::time::Duration::from_secs(1);
+
+        assert!(!crate::probe::probe_target(std::net::Ipv4Addr::new(127, 0, 0, 1), 1, timeout));
+        let result = crate::probe::update_if_healthy(&service, "INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE", &probe, timeout);
+        assert!(matches!(result, Err(crate::error::DnsApiError::NoHealthyTarget)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn sandbox_marks_outcomes() -> Result<(), crate::error::DnsApiError> {
+        let server = serve_once(r#"{"code": 0, "data": "127.0.0.1"}"#);
+        let service = crate::api::Service::builder().sandbox(&server).allow_private_targets(true).build()?;
+
+        let outcome = service.update("INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE")?;
+        assert!(outcome.sandbox, "sandbox outcome was not marked");
+
+        let production = crate::api::Service::builder().sandbox(&server).mirror("https://anondns.net").build();
+        assert!(production.is_err(), "sandbox service accepted the production endpoint");
+
+        Ok(())
+    }
+
+    #[test]
+    fn diagnose_reports_checks() -> Result<(), crate::error::DnsApiError> {
+        let service = crate::api::Service::builder().endpoint(&serve_once("{}")).build()?;
+
+        let diagnosis = crate::diagnose::diagnose(&service, "bad name", None);
+        assert_eq!(diagnosis.api, crate::diagnose::Check::Passed);
+        assert!(!diagnosis.name.is_ok(), "invalid name passed its check");
+        assert!(matches!(diagnosis.token, crate::diagnose::Check::Skipped(_)), "token was checked without a token");
+        assert!(!diagnosis.is_healthy(), "diagnosis with a failed check reported healthy");
+
+        Ok(())
+    }
+
+    #[test]
+    fn local_address_binds_requests() -> Result<(), crate::error::DnsApiError> {
+        let service = crate::api::Service::builder()
+            .allow_private_targets(true)
+            .endpoint(&serve_once(r#"{"code": 0, "data": "127.0.0.1"}"#))
+            .local_address(std::net::Ipv4Addr::LOCALHOST)
+            .build()?;
+
+        let outcome = service.update("INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE")?;
+        assert_eq!(outcome.target, std::net::Ipv4Addr::new(127, 0, 0, 1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn custom_headers_are_sent() -> Result<(), crate::error::DnsApiError> {
+        let (url, request) = serve_capture(r#"{"code": 0, "data": "127.0.0.1"}"#);
+        let service = crate::api::Service::builder()
+            .allow_private_targets(true)
+            .endpoint(&url)
+            .header(reqwest::header::PROXY_AUTHORIZATION, reqwest::header::HeaderValue::from_static("Basic dGVzdDp0ZXN0"))
+            .build()?;
+
+        service.update("INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE")?;
+        let request = request.recv().unwrap().to_lowercase();
+        assert!(request.contains("proxy-authorization: basic dgvzddp0zxn0"), "custom header was not sent");
+
+        Ok(())
+    }
+
+    #[test]
+    fn middleware_sees_requests_and_responses() -> Result<(), crate::error::DnsApiError> {
+        #[derive(Default)]
+        struct Statuses(std::sync::Mutex<Vec<u16>>);
+
+        impl crate::middleware::Middleware for std::sync::Arc<Statuses> {
+            fn on_response(&self, response: &reqwest::blocking::Response) {
+                self.0.lock().unwrap().push(response.status().as_u16());
+            }
+        }
+
+        let (url, request) = serve_capture(r#"{"code": 0, "data": "127.0.0.1"}"#);
+        let statuses = std::sync::Arc::new(Statuses::default());
+        let service = crate::api::Service::builder()
+            .allow_private_targets(true)
+            .endpoint(&url)
+            .middleware(|request: &mut reqwest::blocking::Request| {
+                request.headers_mut().insert("x-signature", reqwest::header::HeaderValue::from_static("signed"));
+            })
+            .middleware(statuses.clone())
+            .build()?;
+
+        service.update("INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE")?;
+        assert!(request.recv().unwrap().to_lowercase().contains("x-signature: signed"), "middleware did not change the request");
+        assert_eq!(*statuses.0.lock().unwrap(), vec![200]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn clones_share_state() -> Result<(), crate::error::DnsApiError> {
+        let service = crate::api::Service::builder()
+            .allow_private_targets(true)
+            .endpoint("http://127.0.0.1:1")
+            .circuit_breaker(1, std::time::Duration::from_secs(60))
+            .build()?;
+
+        let clone = service.clone();
+        assert!(clone.update("INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE").is_err());
+        assert_eq!(service.circuit_breaker().map(|breaker| breaker.state()), Some(crate::breaker::State::Open));
+
+        Ok(())
+    }
+
+    #[test]
+    fn call_timeout_overrides_client() -> Result<(), crate::error::DnsApiError> {
+        // Accepts connections but never answers, so only the timeout ends the request
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let service = crate::api::Service::builder()
+            .allow_private_targets(true)
+            .endpoint(&format!("http://{}", listener.local_addr()?))
+            .build()?
+            .with_options(crate::api::CallOptions::new().timeout(std::time::Duration::from_millis(200)));
+
+        let start = std::time::Instant::now();
+        let result = service.update("INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE");
+        assert!(matches!(result.map_err(crate::error::DnsApiError::into_inner), Err(crate::error::DnsApiError::Reqwest(err)) if err.is_timeout()), "request did not time out");
+        assert!(start.elapsed() < std::time::Duration::from_secs(5), "call timeout was not applied");
+
+        Ok(())
+    }
+
+    #[test]
+    fn watch_yields_failed_lookups() {
+        let service = crate::api::Service::new();
+        let mut watch = service.watch("invalid name", std::time::Duration::from_secs(60));
+
+        // The first poll happens immediately, and a name that cannot resolve yields an error instead of blocking
+        assert!(matches!(watch.next(), Some(Err(_))), "failed lookup was not yielded");
+    }
+
+    #[test]
+    fn address_family_filters_connections() -> Result<(), crate::error::DnsApiError> {
+        let service = crate::api::Service::builder()
+            .allow_private_targets(true)
+            .endpoint(&serve_once(r#"{"code": 0, "data": "127.0.0.1"}"#))
+            .address_family(crate::api::AddressFamily::V6)
+            .build()?;
+
+        // The endpoint only has an IPv4 address, so there is nothing to connect to over IPv6
+        let result = service.update("INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE");
+        assert!(result.is_err(), "connected to an IPv4 endpoint over IPv6");
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_overrides_dns() -> Result<(), crate::error::DnsApiError> {
+        let url = serve_once(r#"{"code": 0, "data": "127.0.0.1"}"#);
+        let address: std::net::SocketAddr = url.trim_start_matches("http://").parse()?;
+
+        // The reserved .invalid domain never resolves, so the request only succeeds through the override
+        let service = crate::api::Service::builder()
+            .allow_private_targets(true)
+            .endpoint(&format!("http://api.anondns.invalid:{}", address.port()))
+            .resolve("api.anondns.invalid", &[address])
+            .build()?;
+
+        service.update("INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE")?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_certs_rejected_for_production() {
+        let production = crate::api::Service::builder().danger_accept_invalid_certs(true).build();
+        assert!(matches!(production, Err(crate::error::DnsApiError::InvalidConfig(_))), "production API accepted invalid certificates");
+
+        let mock = crate::api::Service::builder().endpoint("https://127.0.0.1:8443").danger_accept_invalid_certs(true).build();
+        assert!(mock.is_ok(), "mock endpoint was rejected");
+    }
+
+    #[test]
+    fn request_id_header_matches_outcome() -> Result<(), crate::error::DnsApiError> {
+        let (url, request) = serve_capture(r#"{"code": 0, "data": "127.0.0.1"}"#);
+        let service = crate::api::Service::builder()
+            .allow_private_targets(true)
+            .endpoint(&url)
+            .request_id_header(reqwest::header::HeaderName::from_static("x-request-id"))
+            .build()?;
+
+        let outcome = service.update("INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE")?;
+        let id = outcome.request_id.expect("update sent a request but has no request id");
+        assert!(request.recv().unwrap().contains(&format!("x-request-id: {}", id)), "request id header was not sent");
+
+        Ok(())
+    }
+
+    #[test]
+    fn audit_log_records_failures() -> Result<(), crate::error::DnsApiError> {
+        let path = std::env::temp_dir().join("anondns-api-audit-test");
+        let _ = std::fs::remove_file(&path);
+
+        let service = crate::api::Service::builder()
+            .allow_private_targets(true)
+            .endpoint("http://127.0.0.1:1")
+            .audit_log(crate::audit::AuditLog::open(&path))
+            .build()?;
+
+        let err = service.update("INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 1), "0123456789abcdef").expect_err("nothing listens on the endpoint");
+
+        let entries = crate::audit::AuditLog::open(&path).entries()?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].token.as_deref(), Some("0123..."));
+        assert!(entries[0].error.is_some(), "failed update was logged as a success");
+        assert_eq!(entries[0].request_id, err.request_id().map(ToString::to_string));
+        assert!(entries[0].request_id.is_some(), "the failure was logged without its request ID");
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn validate_target() {
+        use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+        assert!(crate::validate::target(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)), false).is_ok());
+        assert!(crate::validate::target(IpAddr::V4(Ipv4Addr::UNSPECIFIED), true).is_err(), "unspecified address accepted");
+        assert!(crate::validate::target(IpAddr::V4(Ipv4Addr::BROADCAST), true).is_err(), "broadcast address accepted");
+
+        for private in [IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)), IpAddr::V4(Ipv4Addr::new(100, 64, 0, 1)), IpAddr::V6(Ipv6Addr::LOCALHOST), "fd00::1".parse().unwrap()] {
+            assert!(crate::validate::target(private, false).is_err(), "{} accepted as a public target", private);
+            assert!(crate::validate::target(private, true).is_ok(), "{} rejected with private targets allowed", private);
+        }
+    }
+
+    #[test]
+    fn reserved_names_are_rejected() {
+        let reserved = ["www", "mail"];
+
+        assert!(crate::validate::not_reserved("myname", &reserved).is_ok());
+        assert!(crate::validate::not_reserved("WWW", &reserved).is_err(), "reserved name matched case-sensitively");
+        assert!(crate::validate::not_reserved("*.mail", &reserved).is_err(), "wildcard below a reserved name was accepted");
+    }
+
+    #[test]
+    fn strict_mode_rejects_unknown_fields() -> Result<(), crate::error::DnsApiError> {
+        const BODY: &str = r#"{"code": 0, "data": "127.0.0.1", "ttl": 60}"#;
+        let target = std::net::Ipv4Addr::new(127, 0, 0, 1);
+
+        let lenient = crate::api::Service::builder().endpoint(&serve_once(BODY)).allow_private_targets(true).build()?;
+        assert!(lenient.update("INSERT_NAME_HERE", target, "INSERT_TOKEN_HERE").is_ok(), "unknown field failed a lenient update");
+
+        let strict = crate::api::Service::builder().endpoint(&serve_once(BODY)).allow_private_targets(true).strict(true).build()?;
+        let result = strict.update("INSERT_NAME_HERE", target, "INSERT_TOKEN_HERE");
+        assert!(matches!(result.map_err(crate::error::DnsApiError::into_inner), Err(crate::error::DnsApiError::UnexpectedResponse(_))), "strict mode accepted an unknown field");
+
+        Ok(())
+    }
+
+    #[test]
+    fn errors_have_help_and_exit_codes() {
+        let registered = crate::error::DnsApiError::BadRequest((1, String::from("Domain already exists")));
+        assert!(registered.help().contains("update()"), "already registered error did not suggest updating");
+
+        let private = crate::error::DnsApiError::InvalidTarget(String::from("10.0.0.1 is private"));
+        assert!(private.help().contains("private targets"));
+        assert_eq!(private.exit_code(), 2);
+        assert_eq!(crate::error::DnsApiError::BadRequest((1, String::from("Invalid token"))).exit_code(), 3);
+        assert_eq!(crate::error::DnsApiError::CircuitOpen(std::time::Duration::from_secs(1)).exit_code(), 4);
+    }
+
+    #[test]
+    fn register_falls_back_to_update() -> Result<(), crate::error::DnsApiError> {
+        use crate::ext::RegisterResultExt;
+
+        let url = serve_sequence(&[r#"{"code": 1, "data": "Domain already exists"}"#, r#"{"code": 0, "data": "127.0.0.1"}"#]);
+        let service = crate::api::Service::builder().endpoint(&url).allow_private_targets(true).build()?;
+        let mut state = crate::state::State::new();
+        state.set_token("INSERT_NAME_HERE", "INSERT_TOKEN_HERE");
+
+        let subdomain = service.subdomain("INSERT_NAME_HERE");
+        let target = std::net::Ipv4Addr::new(127, 0, 0, 1);
+        let record = subdomain.register(target)
+            .store_token(&mut state)
+            .or_update_with(&subdomain, target, &state)?;
+        assert_eq!(record.token(), "INSERT_TOKEN_HERE");
+
+        Ok(())
+    }
+
+    #[test]
+    fn records_lists_cached_records() -> Result<(), crate::error::DnsApiError> {
+        let path = std::env::temp_dir().join("anondns-api-records-test");
+        let _ = std::fs::remove_file(&path);
+
+        let mut cache = crate::cache::RecordCache::open(&path)?;
+        cache.insert("b", std::net::Ipv6Addr::LOCALHOST.into())?;
+        cache.insert("a", std::net::Ipv4Addr::new(203, 0, 113, 1).into())?;
+
+        let service = crate::api::Service::builder().cache(cache).build()?;
+        let records: Vec<_> = service.records().map(|record| (record.name, record.record_type)).collect();
+        assert_eq!(records, vec![(String::from("a"), "a"), (String::from("b"), "aaaa")]);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn public_ip_skips_wrong_family() -> Result<(), crate::error::DnsApiError> {
+        let client = reqwest::blocking::Client::new();
+        let v6 = serve_once("2001:db8::1");
+        let v4 = serve_once("203.0.113.9\n");
+
+        let address = crate::ip::detect_from(&client, &[&v6, &v4], false)?;
+        assert_eq!(address, std::net::Ipv4Addr::new(203, 0, 113, 9));
+
+        Ok(())
+    }
+
+    #[test]
+    fn renew_is_never_skipped() -> Result<(), crate::error::DnsApiError> {
+        const TEST_TARGET: std::net::Ipv4Addr = std::net::Ipv4Addr::new(127, 0, 0, 1);
+
+        let path = std::env::temp_dir().join("anondns-api-renew-test");
+        let _ = std::fs::remove_file(&path);
+        let mut cache = crate::cache::RecordCache::open(&path)?;
+        cache.insert_record("INSERT_NAME_HERE", crate::cache::CachedRecord { target: TEST_TARGET.into(), confirmed_at: std::time::UNIX_EPOCH })?;
+
+        let service = crate::api::Service::builder()
+            .allow_private_targets(true)
+            .endpoint(&serve_once(r#"{"code": 0, "data": "127.0.0.1"}"#))
+            .cache(cache)
+            .build()?;
+        let mut state = crate::state::State::new();
+        state.set_token("INSERT_NAME_HERE", "INSERT_TOKEN_HERE");
+
+        let renewed = service.renew_due(std::time::Duration::from_secs(3600), &state);
+        assert_eq!(renewed.len(), 1);
+        let outcome = renewed[0].1.as_ref().map_err(|err| err.to_string()).expect("renewing failed");
+        assert!(outcome.request_id.is_some(), "renewal was skipped by the cache");
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn expiring_records_are_reported() -> Result<(), crate::error::DnsApiError> {
+        const DAY: std::time::Duration = std::time::Duration::from_secs(86400);
+
+        let path = std::env::temp_dir().join("anondns-api-expiry-test");
+        let _ = std::fs::remove_file(&path);
+        let mut cache = crate::cache::RecordCache::open(&path)?;
+        cache.insert_record("old", crate::cache::CachedRecord {
+            target: std::net::Ipv4Addr::new(203, 0, 113, 1).into(),
+            confirmed_at: std::time::SystemTime::now() - 25 * DAY
+        })?;
+        cache.insert("fresh", std::net::Ipv4Addr::new(203, 0, 113, 2).into())?;
+
+        let service = crate::api::Service::builder().cache(cache).build()?;
+        let expiring = service.expiring_records(30 * DAY, 7 * DAY);
+        assert_eq!(expiring.iter().map(|record| record.name.as_str()).collect::<Vec<_>>(), vec!["old"]);
+        assert!(expiring[0].expires_in(30 * DAY) <= 5 * DAY);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn breaker_backs_off() {
+        const COOLDOWN: std::time::Duration = std::time::Duration::from_millis(20);
+        let mut breaker = crate::breaker::CircuitBreaker::new(1, COOLDOWN).backoff(5 * COOLDOWN);
+
+        let (first, second) = (breaker.check().unwrap(), breaker.check().unwrap());
+        breaker.record_failure(first);
+        assert_eq!(breaker.current_cooldown(), COOLDOWN);
+        breaker.record_failure(second);
+        assert_eq!(breaker.current_cooldown(), COOLDOWN, "a failure that was not a trial backed off");
+
+        std::thread::sleep(COOLDOWN);
+        let trial = breaker.check().unwrap();
+        breaker.record_failure(trial);
+        assert_eq!(breaker.current_cooldown(), 2 * COOLDOWN);
+
+        std::thread::sleep(2 * COOLDOWN);
+        let trial = breaker.check().unwrap();
+        breaker.record_failure(trial);
+        assert_eq!(breaker.current_cooldown(), 4 * COOLDOWN);
+
+        std::thread::sleep(4 * COOLDOWN);
+        let trial = breaker.check().unwrap();
+        breaker.record_failure(trial);
+        assert_eq!(breaker.current_cooldown(), 5 * COOLDOWN, "cool-down grew past its cap");
+
+        std::thread::sleep(5 * COOLDOWN);
+        let trial = breaker.check().unwrap();
+        breaker.record_success(trial);
+        assert_eq!(breaker.current_cooldown(), COOLDOWN);
+    }
+
+    #[test]
+    fn half_open_breaker_lets_one_trial_through() {
+        const COOLDOWN: std::time::Duration = std::time::Duration::from_millis(20);
+        let mut breaker = crate::breaker::CircuitBreaker::new(1, COOLDOWN).backoff(4 * COOLDOWN);
+        let late = breaker.check().unwrap();
+        let call = breaker.check().unwrap();
+        breaker.record_failure(call);
+
+        std::thread::sleep(COOLDOWN);
+        assert_eq!(breaker.state(), crate::breaker::State::HalfOpen);
+        let trial = breaker.check().expect("the trial call was rejected");
+        assert!(breaker.check().is_err(), "a second call got through while the trial was in flight");
+
+        // A call from before the breaker opened failing late is not the trial's outcome
+        breaker.record_failure(late);
+        assert_eq!(breaker.current_cooldown(), COOLDOWN, "a late failure backed off");
+        assert!(breaker.check().is_err(), "a late failure ended the trial");
+
+        breaker.record_success(trial);
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn bulk_results_keep_input_order() -> Result<(), crate::error::DnsApiError> {
+        // Invalid names fail before any request is sent, so the order of the errors shows the order of the results
+        let service = crate::api::Service::builder().endpoint("http://127.0.0.1:1").build()?;
+        let target: std::net::IpAddr = std::net::Ipv4Addr::new(203, 0, 113, 1).into();
+        let records = [("bad name 0", target), ("bad name 1", target), ("bad name 2", target), ("bad name 3", target)];
+
+        let results = crate::bulk::register_all(&service, &records, 3);
+        for (i, result) in results.iter().enumerate() {
+            let message = result.as_ref().map(|_| ()).unwrap_err().to_string();
+            assert!(message.contains(&format!("bad name {}", i)), "result {} is out of order: {}", i, message);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn protocol_round_trip() -> Result<(), crate::error::DnsApiError> {
+        let target: std::net::IpAddr = std::net::Ipv6Addr::LOCALHOST.into();
+        let request = crate::protocol::update(crate::api::ApiVersion::V1, "name", "token", target);
+        assert_eq!(request.url("https://example.test"), "https://example.test/api/set/name.anondns.net/token/aaaa/::1");
+        let request = crate::protocol::update(crate::api::ApiVersion::V1, "*.name", "to/ken?x", target);
+        assert_eq!(request.path, "/api/set/%2A.name.anondns.net/to%2Fken%3Fx/aaaa/::1", "path segments were not encoded");
+
+        let response = crate::protocol::parse(br#"{"code":1,"data":"bad token","extra":true}"#)?;
+        assert_eq!(response.unknown_fields(), ["extra"]);
+        assert!(matches!(response.into_result(), Err(crate::error::DnsApiError::BadRequest((1, _)))));
+        assert!(crate::protocol::parse(b"<html>").is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_responses_are_accepted() -> Result<(), crate::error::DnsApiError> {
+        let (url, request) = serve_capture(r#"{"code": 0, "data": "127.0.0.1"}"#);
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint(&url).build()?;
+
+        service.update("INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE")?;
+        let request = request.recv().unwrap().to_lowercase();
+        assert!(request.contains("accept-encoding: gzip, br"), "compression was not negotiated: {}", request);
+
+        Ok(())
+    }
+
+    #[test]
+    fn pooled_connections_are_reused() -> Result<(), crate::error::DnsApiError> {
+        use std::io::{Read, Write};
+
+        // Answers requests on a single connection, so a second update only succeeds if it is reused
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let body = r#"{"code": 0, "data": "127.0.0.1"}"#;
+            for _ in 0..2 {
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request);
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        let service = crate::api::Service::builder()
+            .allow_private_targets(true)
+            .endpoint(&url)
+            .timeout(std::time::Duration::from_secs(5))
+            .pool_max_idle_per_host(1)
+            .pool_idle_timeout(Some(std::time::Duration::from_secs(600)))
+            .tcp_keepalive(std::time::Duration::from_secs(60))
+            .build()?;
+
+        service.update("INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE")?;
+        service.update("INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE")?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn urgent_updates_preempt_background() -> Result<(), crate::error::DnsApiError> {
+        use crate::queue::Priority;
+
+        let url = serve_once(r#"{"code": 0, "data": "127.0.0.2"}"#);
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint(&url).build()?;
+
+        let mut queue = crate::queue::UpdateQueue::new(std::time::Duration::from_secs(30));
+        queue.push_with_priority("batch", std::net::Ipv4Addr::new(127, 0, 0, 1), String::from("token"), Priority::Background);
+        queue.push_with_priority("server", std::net::Ipv4Addr::new(127, 0, 0, 2), String::from("token"), Priority::Urgent);
+        assert_eq!(queue.next_due(), Some(std::time::Duration::ZERO));
+
+        let sent = queue.flush_due(&service);
+        assert_eq!(sent.len(), 1, "background update was sent before its window elapsed");
+        assert_eq!(sent[0].0, "server");
+        assert!(sent[0].1.is_ok());
+        assert_eq!(queue.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn outcomes_carry_the_record() -> Result<(), crate::error::DnsApiError> {
+        let url = serve_once(r#"{"code": 0, "data": "127.0.0.1", "name": "example.anondns.net", "type": "a", "status": 1, "updated": "2024-01-02 03:04:05"}"#);
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint(&url).build()?;
+
+        let outcome = service.update("example", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE")?;
+        assert_eq!(outcome.record, crate::api::Record {
+            name: Some(String::from("example.anondns.net")),
+            record_type: Some(String::from("a")),
+            status: Some(1),
+            updated: Some(String::from("2024-01-02 03:04:05")),
+            extra: std::collections::HashMap::new()
+        });
+
+        Ok(())
+    }
+
+    #[test]
+    fn raw_envelope_keeps_everything() -> Result<(), crate::error::DnsApiError> {
+        let url = serve_once(r#"{"code": 1, "data": "name taken", "hint": "try again"}"#);
+        let service = crate::api::Service::builder().endpoint(&url).build()?;
+
+        // Not validated, so even a loopback target is sent
+        let envelope = service.register_raw("taken", std::net::Ipv4Addr::new(127, 0, 0, 1))?;
+        assert_eq!(envelope.status, reqwest::StatusCode::OK);
+        assert_eq!(envelope.headers["content-type"], "application/json");
+
+        let response = envelope.parse()?;
+        assert_eq!((response.code, response.data.as_str()), (1, "name taken"));
+        assert_eq!(response.unknown["hint"], "try again");
+
+        Ok(())
+    }
+
+    #[test]
+    fn drifted_records_bypass_the_cache() -> Result<(), crate::error::DnsApiError> {
+        const TEST_TARGET: std::net::Ipv4Addr = std::net::Ipv4Addr::new(127, 0, 0, 1);
+
+        let path = std::env::temp_dir().join("anondns-api-drift-test");
+        let _ = std::fs::remove_file(&path);
+        let mut cache = crate::cache::RecordCache::open(&path)?;
+        cache.insert("drifted", TEST_TARGET.into())?;
+
+        let (url, request) = serve_capture(r#"{"code": 0, "data": "127.0.0.1"}"#);
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint(&url).cache(cache).build()?;
+
+        let plan = crate::sync::Plan {
+            records: vec![crate::sync::PlannedRecord {
+                record: crate::sync::DesiredRecord::new("drifted", TEST_TARGET).with_token("INSERT_TOKEN_HERE"),
+                change: crate::sync::Change::Update { live: Vec::new() }
+            }]
+        };
+        let results = plan.apply(&service);
+        assert!(matches!(results[0].1, Ok(crate::sync::Action::Updated(_))));
+        assert!(request.recv().is_ok(), "the cache skipped a drifted record");
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_sends_nothing() -> Result<(), crate::error::DnsApiError> {
+        // Nothing listens on the endpoint, so operations only succeed if they are simulated
+        let service = crate::api::Service::builder().endpoint("http://127.0.0.1:1").dry_run(true).build()?;
+        let target = std::net::Ipv4Addr::new(203, 0, 113, 1);
+
+        let registered = service.register("example", target)?;
+        assert_eq!(registered.token, crate::api::DRY_RUN_TOKEN);
+        assert!(registered.dry_run);
+
+        let updated = service.update("example", target, &registered.token)?;
+        assert_eq!(updated.target, std::net::IpAddr::from(target));
+        assert!(service.register("bad name", target).is_err(), "dry run skipped validation");
+
+        let envelope = service.update_raw("example", target, "INSERT_TOKEN_HERE")?;
+        assert_eq!(envelope.parse()?.data, target.to_string());
+        assert_eq!(service.register_raw("example", target)?.parse()?.token.as_deref(), Some(crate::api::DRY_RUN_TOKEN));
+
+        Ok(())
+    }
+
+    #[test]
+    fn retries_follow_the_call_policy() -> Result<(), crate::error::DnsApiError> {
+        use std::io::{Read, Write};
+        use crate::api::{CallOptions, RetryPolicy};
+
+        // Answers 503 once, then succeeds
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}", listener.local_addr()?);
+        std::thread::spawn(move || {
+            let body = r#"{"code": 0, "data": "127.0.0.1"}"#;
+            let responses = [
+                String::from("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+                format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body),
+                String::from("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            ];
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let _ = stream.read(&mut [0; 4096]);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        let service = crate::api::Service::builder()
+            .allow_private_targets(true)
+            .endpoint(&url)
+            .retry(RetryPolicy::new(2, std::time::Duration::from_millis(10)))
+            .build()?;
+        service.update("INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE")?;
+
+        let interactive = service.with_options(CallOptions::new().retry(RetryPolicy::none()));
+        let result = interactive.update("INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 2), "INSERT_TOKEN_HERE");
+        assert!(matches!(result.map_err(crate::error::DnsApiError::into_inner), Err(crate::error::DnsApiError::Reqwest(err)) if err.is_status()), "per-call policy did not disable retries");
+
+        Ok(())
+    }
+
+    #[test]
+    fn redirects_to_other_hosts_are_refused() -> Result<(), crate::error::DnsApiError> {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}", listener.local_addr()?);
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0; 4096]);
+            let response = "HTTP/1.1 302 Found\r\nLocation: http://portal.example/login\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint(&url).build()?;
+        let result = service.update("INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE");
+        match result.map_err(crate::error::DnsApiError::into_inner) {
+            Err(crate::error::DnsApiError::UnexpectedResponse(reason)) => assert!(reason.contains("portal.example"), "{}", reason),
+            other => panic!("redirect was not refused: {:?}", other)
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_responses_are_rejected() -> Result<(), crate::error::DnsApiError> {
+        let url = serve_once(r#"{"code": 0, "data": "127.0.0.1", "padding": "................................................"}"#);
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint(&url).max_response_size(64).build()?;
+
+        let result = service.update("INSERT_NAME_HERE", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE");
+        assert!(matches!(result.map_err(crate::error::DnsApiError::into_inner), Err(crate::error::DnsApiError::UnexpectedResponse(reason)) if reason.contains("64 bytes")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn html_pages_are_service_unavailable() {
+        let page = b"<!DOCTYPE html>\n<html>\n  <title>Just a moment...</title>\n</html>";
+        let result = crate::protocol::parse_with_content_type(Some("text/html; charset=UTF-8"), page);
+        match result {
+            Err(crate::error::DnsApiError::ServiceUnavailable(snippet)) => assert!(snippet.starts_with("<!DOCTYPE html> <html> <title>Just a moment")),
+            other => panic!("html page was not recognised: {:?}", other)
+        }
+
+        let result = crate::protocol::parse_with_content_type(Some("application/json"), b"{\"code\": ");
+        assert!(matches!(result, Err(crate::error::DnsApiError::Json(_))));
+    }
+
+    #[cfg(feature = "dot")]
+    #[test]
+    fn dns_wire_format() -> std::io::Result<()> {
+        let query = crate::resolver::query(7, "a.anondns.net", 1)?;
+        assert_eq!(&query[12..], b"\x01a\x07anondns\x03net\x00\x00\x01\x00\x01");
+
+        // The query echoed back as a response with one answer whose name points at the question
+        let mut response = query.clone();
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 1;
+        response.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 203, 0, 113, 1]);
+        assert_eq!(crate::resolver::parse_answers(&response, 7)?, [std::net::IpAddr::from([203, 0, 113, 1])]);
+
+        response[3] = 0x83;
+        assert_eq!(crate::resolver::parse_answers(&response, 7).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        assert!(crate::resolver::parse_answers(&response[..20], 8).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn dual_stack_rolls_back_the_a_record() -> Result<(), crate::error::DnsApiError> {
+        const PREVIOUS: std::net::Ipv4Addr = std::net::Ipv4Addr::new(127, 0, 0, 9);
+
+        let path = std::env::temp_dir().join("anondns-api-dual-stack-test");
+        let _ = std::fs::remove_file(&path);
+        let mut cache = crate::cache::RecordCache::open(&path)?;
+        cache.insert("dual", PREVIOUS.into())?;
+
+        let url = serve_sequence(&[
+            r#"{"code": 0, "data": "127.0.0.1"}"#,
+            r#"{"code": 1, "data": "invalid address"}"#,
+            r#"{"code": 0, "data": "127.0.0.9"}"#
+        ]);
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint(&url).cache(cache).build()?;
+
+        let result = service.update_dual_stack("dual", std::net::Ipv4Addr::new(127, 0, 0, 1), std::net::Ipv6Addr::LOCALHOST, "INSERT_TOKEN_HERE");
+        assert!(matches!(result.map_err(crate::error::DnsApiError::into_inner), Err(crate::error::DnsApiError::BadRequest(_))));
+        let cached = service.cache().and_then(|cache| cache.get("dual", "a").map(|record| record.target));
+        assert_eq!(cached, Some(PREVIOUS.into()), "the A record was not rolled back");
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn response_codes_are_parsed_leniently() -> Result<(), crate::error::DnsApiError> {
+        assert_eq!(crate::protocol::parse(br#"{"code": "1", "data": "bad token"}"#)?.code, 1);
+        assert_eq!(crate::protocol::parse(br#"{"code": 0, "data": "203.0.113.1"}"#)?.code, 0);
+        assert_eq!(crate::protocol::parse(br#"{"data": "203.0.113.1"}"#)?.code, 0);
+        assert!(crate::protocol::parse(br#"{"code": "zero", "data": ""}"#).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn ndjson_import_streams_records() -> Result<(), crate::error::DnsApiError> {
+        let service = crate::api::Service::builder().endpoint("http://127.0.0.1:1").dry_run(true).build()?;
+        let input = concat!(
+            "{\"name\": \"first\", \"target\": \"203.0.113.1\"}\n",
+            "\n",
+            "not json\n",
+            "{\"name\": \"second\", \"target\": \"203.0.113.2\", \"token\": \"INSERT_TOKEN_HERE\"}\n"
+        );
+
+        let imported: Vec<_> = crate::bulk::import(&service, input.as_bytes()).collect();
+        assert_eq!(imported.len(), 3, "blank line was not skipped");
+        assert!(matches!(imported[0].result, Ok(crate::sync::Action::Registered(_))));
+        assert_eq!((imported[1].line, imported[1].name.as_deref()), (3, None));
+        assert!(matches!(imported[1].result, Err(crate::error::DnsApiError::Json(_))));
+        assert!(matches!(imported[2].result, Ok(crate::sync::Action::Updated(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_reports_progress() -> Result<(), crate::error::DnsApiError> {
+        let service = crate::api::Service::builder().endpoint("http://127.0.0.1:1").dry_run(true).build()?;
+        let target: std::net::IpAddr = std::net::Ipv4Addr::new(203, 0, 113, 1).into();
+        let records = [("first", target), ("bad name", target), ("third", target)];
+
+        let seen = std::sync::Mutex::new(Vec::new());
+        crate::bulk::Bulk::new(&service)
+            .threads(2)
+            .on_progress(|progress| seen.lock().unwrap().push((progress.done, progress.total, progress.error.is_some())))
+            .register_all(&records);
+
+        let seen = seen.into_inner().unwrap();
+        let done: Vec<usize> = seen.iter().map(|(done, _, _)| *done).collect();
+        assert_eq!(done, [1, 2, 3], "progress was not reported in order");
+        assert_eq!(seen.iter().filter(|(_, total, failed)| *total == 3 && *failed).count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cancelled_bulk_keeps_partial_results() -> Result<(), crate::error::DnsApiError> {
+        let service = crate::api::Service::builder().endpoint("http://127.0.0.1:1").dry_run(true).build()?;
+        let target: std::net::IpAddr = std::net::Ipv4Addr::new(203, 0, 113, 1).into();
+        let records = [("first", target), ("second", target), ("third", target)];
+
+        // Cancels from the progress callback once the first record is done
+        let cancel = crate::bulk::Cancel::new();
+        let results = crate::bulk::Bulk::new(&service)
+            .threads(1)
+            .cancel_with(&cancel)
+            .on_progress(|_| cancel.cancel())
+            .register_all(&records);
+
+        assert!(results[0].is_ok());
+        assert!(results[1..].iter().all(|result| matches!(result, Err(crate::error::DnsApiError::Cancelled))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_ddclient_and_inadyn() {
+        let ddclient = "# comment\nprotocol=dyndns2, password=first home.anondns.net\npassword=\"second\" \\\n  nas.anondns.net, other.example.com\nlost.anondns.net\n";
+        let state = crate::migrate::from_ddclient(ddclient);
+        let tokens: Vec<_> = state.records.iter().map(|record| (record.name.as_str(), record.token.as_ref().map(|token| token.as_ref()))).collect();
+        assert_eq!(tokens, [("home", Some("first")), ("nas", Some("second")), ("lost", Some("second"))]);
+
+        let inadyn = "period = 300\nprovider default@dyndns.org {\n  password = skipped\n  hostname = other.example.com\n}\ncustom anondns {\n  username = ignored\n  password = \"token\" # trailing\n  hostname = { \"home.anondns.net\", 'nas.anondns.net.' }\n}\ncustom empty {\n  hostname = bare.anondns.net\n}\n";
+        let state = crate::migrate::from_inadyn(inadyn);
+        let tokens: Vec<_> = state.records.iter().map(|record| (record.name.as_str(), record.token.as_ref().map(|token| token.as_ref()))).collect();
+        assert_eq!(tokens, [("home", Some("token")), ("nas", Some("token"))]);
+
+        let custom = "password=token home.dyn.example.org, nas.anondns.net, evildyn.example.org\n";
+        let state = crate::migrate::from_ddclient_in(custom, "dyn.example.org");
+        let names: Vec<_> = state.records.iter().map(|record| record.name.as_str()).collect();
+        assert_eq!(names, ["home"]);
+        assert_eq!(crate::migrate::from_inadyn_in("custom dyn {\n  password = token\n  hostname = nas.dyn.example.org\n}\n", "dyn.example.org.").records[0].name, "nas");
+    }
+
+    #[test]
+    fn router_ip_nat_pmp() {
+        let router = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = router.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut request = [0; 2];
+            let (_, client) = router.recv_from(&mut request).unwrap();
+            assert_eq!(request, [0, 0]);
+            router.send_to(&[0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7], client).unwrap();
+
+            let (_, client) = router.recv_from(&mut request).unwrap();
+            router.send_to(&[0, 128, 0, 3, 0, 0, 0, 1, 0, 0, 0, 0], client).unwrap();
+        });
+
+        let timeout = std::time::Duration::from_secs(5);
+        assert_eq!(crate::ip::router_ip_at(addr, timeout).unwrap(), std::net::Ipv4Addr::new(203, 0, 113, 7));
+        assert!(matches!(crate::ip::router_ip_at(addr, timeout), Err(crate::error::DnsApiError::UnexpectedResponse(_))));
+    }
+
+    #[test]
+    fn outcomes_keep_unknown_fields() -> Result<(), crate::error::DnsApiError> {
+        let url = serve_once(r#"{"code": 0, "data": "127.0.0.1", "ttl": 300, "expires": "2025-01-01"}"#);
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint(&url).build()?;
+
+        let outcome = service.update("example", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE")?;
+        assert_eq!(outcome.record.extra["ttl"], 300);
+        assert_eq!(outcome.record.extra["expires"], "2025-01-01");
+        assert_eq!(outcome.record.extra.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn zone_replaces_the_parent_domain() -> Result<(), crate::error::DnsApiError> {
+        let (url, request) = serve_capture(r#"{"code": 0, "data": "127.0.0.1"}"#);
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint(&url).zone(".dyn.example.org.").build()?;
+        assert_eq!(service.zone(), "dyn.example.org");
+
+        service.update("home", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE")?;
+        let request = request.recv().unwrap();
+        assert!(request.starts_with("GET /api/set/home.dyn.example.org/INSERT_TOKEN_HERE/a/127.0.0.1 "), "{}", request);
+
+        Ok(())
+    }
+
+    #[test]
+    fn outcomes_report_timing() -> Result<(), crate::error::DnsApiError> {
+        let url = serve_once(r#"{"code": 0, "data": "127.0.0.1"}"#);
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint(&url).build()?;
+
+        let outcome = service.update("example", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE")?;
+        let timing = outcome.timing.expect("a sent request has timing");
+        assert_eq!(timing.attempts, 1);
+        assert!(timing.total >= timing.response);
+
+        let dry = crate::api::Service::builder().endpoint("http://127.0.0.1:1").dry_run(true).build()?;
+        assert_eq!(dry.update("example", std::net::Ipv4Addr::new(203, 0, 113, 1), "INSERT_TOKEN_HERE")?.timing, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn state_labels_select_records() -> Result<(), crate::error::DnsApiError> {
+        let mut state = crate::state::State::new();
+        state.set_token("web", "INSERT_TOKEN_HERE");
+        state.set_label("web", "env", "prod");
+        state.set_label("staging", "env", "test");
+        state.set_label("db", "env", "prod");
+        state.set_label("db", "owner", "alex");
+
+        let prod: Vec<_> = state.select("env", "prod").records.into_iter().map(|record| record.name).collect();
+        assert_eq!(prod, ["web", "db"]);
+        assert!(state.select("owner", "nobody").records.is_empty());
+
+        let json = serde_json::to_string(&state)?;
+        assert_eq!(serde_json::from_str::<crate::state::State>(&json)?, state);
+        let unlabelled: crate::state::State = serde_json::from_str(r#"{"version": 1, "records": [{"name": "old"}]}"#)?;
+        assert!(unlabelled.records[0].labels.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn queue_damps_flapping_targets() -> Result<(), crate::error::DnsApiError> {
+        let url = serve_once(r#"{"code": 0, "data": "127.0.0.1"}"#);
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint(&url).build()?;
+        let damping = std::time::Duration::from_millis(100);
+        let mut queue = crate::queue::UpdateQueue::new(std::time::Duration::ZERO).damping(damping);
+
+        queue.push("home", std::net::Ipv4Addr::new(127, 0, 0, 1), String::from("token"));
+        assert!(queue.flush_due(&service).is_empty(), "an unstable target was sent");
+        assert!(queue.next_due().unwrap() > std::time::Duration::ZERO);
+
+        std::thread::sleep(damping);
+        let sent = queue.flush_due(&service);
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].1.is_ok());
+
+        queue.push("home", std::net::Ipv4Addr::new(127, 0, 0, 2), String::from("token"));
+        queue.push("home", std::net::Ipv4Addr::new(127, 0, 0, 1), String::from("token"));
+        assert!(queue.is_empty(), "a change that reverted was kept");
+
+        Ok(())
+    }
+
+    #[test]
+    fn cache_keeps_a_and_aaaa_records_apart() -> Result<(), crate::error::DnsApiError> {
+        const V4: std::net::Ipv4Addr = std::net::Ipv4Addr::new(127, 0, 0, 1);
+        const V6: std::net::Ipv6Addr = std::net::Ipv6Addr::LOCALHOST;
+
+        let path = std::env::temp_dir().join("anondns-api-cache-types-test");
+        let _ = std::fs::remove_file(&path);
+        let mut cache = crate::cache::RecordCache::open(&path)?;
+        cache.insert("home", V4.into())?;
+        cache.insert("home", V6.into())?;
+
+        let reopened = crate::cache::RecordCache::open(&path)?;
+        assert_eq!(reopened.get("home", "a").map(|record| record.target), Some(V4.into()));
+        assert_eq!(reopened.get("home", "aaaa").map(|record| record.target), Some(V6.into()));
+
+        // Alternating updates of unchanged A and AAAA targets are both skipped, without reaching this endpoint
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint("http://127.0.0.1:1").cache(reopened).build()?;
+        let a = service.update("home", V4, "INSERT_TOKEN_HERE")?;
+        let aaaa = service.update("home", V6, "INSERT_TOKEN_HERE")?;
+        assert_eq!((a.request_id, a.previous), (None, Some(V4.into())));
+        assert_eq!((aaaa.request_id, aaaa.previous), (None, Some(V6.into())));
+
+        let url = serve_once(r#"{"code": 0, "data": "::2"}"#);
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint(&url).cache(service.cache().unwrap()).build()?;
+        let changed = service.update("home", "::2".parse::<std::net::Ipv6Addr>().unwrap(), "INSERT_TOKEN_HERE")?;
+        assert_eq!(changed.previous, Some(V6.into()), "an AAAA update reported the A address as previous");
+        assert_eq!(service.cache().unwrap().get("home", "a").map(|record| record.target), Some(V4.into()));
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn dual_stack_rolls_back_after_a_previous_dual_stack_run() -> Result<(), crate::error::DnsApiError> {
+        const PREVIOUS: std::net::Ipv4Addr = std::net::Ipv4Addr::new(127, 0, 0, 9);
+
+        let path = std::env::temp_dir().join("anondns-api-dual-stack-steady-test");
+        let _ = std::fs::remove_file(&path);
+        let mut cache = crate::cache::RecordCache::open(&path)?;
+        cache.insert("dual", PREVIOUS.into())?;
+        cache.insert("dual", std::net::Ipv6Addr::LOCALHOST.into())?;
+
+        let url = serve_sequence(&[
+            r#"{"code": 0, "data": "127.0.0.1"}"#,
+            r#"{"code": 1, "data": "invalid address"}"#,
+            r#"{"code": 0, "data": "127.0.0.9"}"#
+        ]);
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint(&url).cache(cache).build()?;
+
+        let v6 = "::2".parse::<std::net::Ipv6Addr>().unwrap();
+        let result = service.update_dual_stack("dual", std::net::Ipv4Addr::new(127, 0, 0, 1), v6, "INSERT_TOKEN_HERE");
+        assert!(matches!(result.map_err(crate::error::DnsApiError::into_inner), Err(crate::error::DnsApiError::BadRequest(_))));
+        let cached = service.cache().and_then(|cache| cache.get("dual", "a").map(|record| record.target));
+        assert_eq!(cached, Some(PREVIOUS.into()), "the A record was not rolled back");
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn only_diagnose_with_token_updates() -> Result<(), crate::error::DnsApiError> {
+        let path = std::env::temp_dir().join("anondns-api-diagnose-history-test");
+        let _ = std::fs::remove_file(&path);
+        let target: std::net::IpAddr = std::net::Ipv4Addr::new(127, 0, 0, 5).into();
+
+        let service = crate::api::Service::builder()
+            .allow_private_targets(true)
+            .endpoint(&serve_once("{}"))
+            .history(crate::history::History::open(&path))
+            .build()?;
+        let diagnosis = crate::diagnose::diagnose(&service, "example", Some(target));
+        assert!(matches!(diagnosis.token, crate::diagnose::Check::Skipped(_)));
+        assert!(service.history().unwrap().entries()?.is_empty(), "a read-only diagnosis changed the record");
+
+        let service = crate::api::Service::builder()
+            .allow_private_targets(true)
+            .endpoint(&serve_sequence(&["{}", r#"{"code": 0, "data": "127.0.0.5"}"#]))
+            .history(crate::history::History::open(&path))
+            .build()?;
+        let diagnosis = crate::diagnose::diagnose_with_token(&service, "example", "INSERT_TOKEN_HERE", target);
+        assert_eq!(diagnosis.token, crate::diagnose::Check::Passed);
+        let entries = service.history().unwrap().entries()?;
+        assert_eq!(entries.iter().map(|entry| entry.new).collect::<Vec<_>>(), [target], "the token check did not use the given target");
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn strict_mode_requires_an_integer_code() -> Result<(), crate::error::DnsApiError> {
+        let target = std::net::Ipv4Addr::new(127, 0, 0, 1);
+
+        let lenient = crate::api::Service::builder().endpoint(&serve_once(r#"{"code": "0", "data": "127.0.0.1"}"#)).allow_private_targets(true).build()?;
+        assert!(lenient.update("INSERT_NAME_HERE", target, "INSERT_TOKEN_HERE").is_ok(), "a string code failed a lenient update");
+
+        let strict = crate::api::Service::builder().endpoint(&serve_once(r#"{"code": "0", "data": "127.0.0.1"}"#)).allow_private_targets(true).strict(true).build()?;
+        let result = strict.update("INSERT_NAME_HERE", target, "INSERT_TOKEN_HERE");
+        assert!(matches!(result.map_err(crate::error::DnsApiError::into_inner), Err(crate::error::DnsApiError::UnexpectedResponse(_))), "strict mode accepted a string code");
+
+        let missing = crate::api::Service::builder().endpoint(&serve_once(r#"{"data": "127.0.0.1"}"#)).allow_private_targets(true).build()?;
+        assert!(missing.update("INSERT_NAME_HERE", target, "INSERT_TOKEN_HERE").is_ok(), "a response without a code failed a lenient update");
+
+        let strict = crate::api::Service::builder().endpoint(&serve_once(r#"{"data": "127.0.0.1"}"#)).allow_private_targets(true).strict(true).build()?;
+        let result = strict.update("INSERT_NAME_HERE", target, "INSERT_TOKEN_HERE");
+        assert!(matches!(result.map_err(crate::error::DnsApiError::into_inner), Err(crate::error::DnsApiError::UnexpectedResponse(_))), "strict mode accepted a response without a code");
+
+        Ok(())
+    }
+
+    #[test]
+    fn lock_update_replaces_the_file_atomically() -> std::io::Result<()> {
+        let path = std::env::temp_dir().join("anondns-api-atomic-update-test");
+        let temp = std::env::temp_dir().join("anondns-api-atomic-update-test.tmp");
+        let _ = std::fs::remove_file(&path);
+        // A temporary file left behind by a crashed writer is neither read nor a problem
+        std::fs::write(&temp, "partial")?;
+
+        crate::lock::update(&path, |current| (format!("{}first\n", current), ()))?;
+        crate::lock::update(&path, |current| (format!("{}second\n", current), ()))?;
+        assert_eq!(crate::lock::read(&path)?.as_deref(), Some("first\nsecond\n"));
+        assert!(!temp.exists(), "the temporary file was left behind");
+
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn offline_queue_keeps_rate_limited_operations() -> Result<(), crate::error::DnsApiError> {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0; 4096]);
+            stream.write_all(b"HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+        });
+
+        let path = std::env::temp_dir().join("anondns-api-offline-rate-limit-test");
+        let _ = std::fs::remove_file(&path);
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint(&url).build()?;
+        let mut queue = crate::offline::OfflineQueue::open(&path)?;
+
+        let operation = crate::offline::Operation::Update {
+            subdomain: String::from("INSERT_NAME_HERE"),
+            target: std::net::Ipv4Addr::new(127, 0, 0, 1).into(),
+            token: String::from("INSERT_TOKEN_HERE")
+        };
+        assert!(queue.run(&service, operation)?.is_none());
+        assert_eq!(queue.pending().count(), 1, "a rate-limited operation was dropped");
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn request_errors_carry_the_request_id() -> Result<(), crate::error::DnsApiError> {
+        let url = serve_once(r#"{"code": 1, "data": "Invalid token"}"#);
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint(&url).build()?;
+
+        let err = service.update("example", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE").unwrap_err();
+        let id = err.request_id().expect("a failed request has no identifier").to_string();
+        assert!(err.to_string().ends_with(&format!("(request {})", id)), "{}", err);
+        assert!(matches!(err.inner(), crate::error::DnsApiError::BadRequest(_)));
+        assert_eq!(err.exit_code(), 3);
+
+        let local = service.update("bad name", std::net::Ipv4Addr::new(127, 0, 0, 1), "INSERT_TOKEN_HERE").unwrap_err();
+        assert!(local.request_id().is_none(), "an error raised before sending has an identifier");
+
+        Ok(())
+    }
+
+    #[test]
+    fn queue_keeps_a_and_aaaa_updates_apart() -> Result<(), crate::error::DnsApiError> {
+        let service = crate::api::Service::builder().endpoint("http://127.0.0.1:1").dry_run(true).build()?;
+        let mut queue = crate::queue::UpdateQueue::new(std::time::Duration::ZERO).damping(std::time::Duration::ZERO);
+        let v4 = std::net::Ipv4Addr::new(203, 0, 113, 1);
+        let v6 = "2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap();
+
+        queue.push("dual", v4, String::from("token"));
+        queue.push("dual", v6, String::from("token"));
+        assert_eq!(queue.len(), 2, "the A and AAAA updates were coalesced");
+
+        let sent = queue.flush(&service);
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|(name, result)| name == "dual" && result.is_ok()));
+
+        // Each record is damped against the target last sent for it, not for the other record type
+        queue.push("dual", v6, String::from("token"));
+        assert!(queue.is_empty(), "the AAAA target already sent was queued again");
+        queue.push("dual", std::net::Ipv4Addr::new(203, 0, 113, 2), String::from("token"));
+        assert_eq!(queue.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn renew_due_renews_both_records_of_a_dual_stack_name() -> Result<(), crate::error::DnsApiError> {
+        let path = std::env::temp_dir().join("anondns-api-renew-dual-stack-test");
+        let _ = std::fs::remove_file(&path);
+        let v4 = std::net::IpAddr::from(std::net::Ipv4Addr::new(203, 0, 113, 1));
+        let v6 = std::net::IpAddr::from("2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap());
+        let mut cache = crate::cache::RecordCache::open(&path)?;
+        cache.insert_record("dual", crate::cache::CachedRecord { target: v4, confirmed_at: std::time::UNIX_EPOCH })?;
+        cache.insert_record("dual", crate::cache::CachedRecord { target: v6, confirmed_at: std::time::UNIX_EPOCH })?;
+
+        // The server confirms whatever it is asked to set, so renewing the A record twice fails the second time
+        let service = crate::api::Service::builder()
+            .allow_private_targets(true)
+            .endpoint(&serve_sequence(&[r#"{"code": 0, "data": "203.0.113.1"}"#, r#"{"code": 0, "data": "2001:db8::1"}"#]))
+            .cache(cache)
+            .build()?;
+        let mut state = crate::state::State::new();
+        state.set_token("dual", "INSERT_TOKEN_HERE");
+
+        let renewed = service.renew_due(std::time::Duration::from_secs(3600), &state);
+        let targets = renewed.into_iter()
+            .map(|(_, result)| result.map(|outcome| outcome.target))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(targets, vec![v4, v6]);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn state_tokens_cover_both_records_of_a_dual_stack_name() -> Result<(), crate::error::DnsApiError> {
+        let path = std::env::temp_dir().join("anondns-api-state-dual-stack-test");
+        let _ = std::fs::remove_file(&path);
+        let mut cache = crate::cache::RecordCache::open(&path)?;
+        cache.insert_record("dual", crate::cache::CachedRecord { target: std::net::Ipv4Addr::new(203, 0, 113, 1).into(), confirmed_at: std::time::UNIX_EPOCH })?;
+        cache.insert_record("dual", crate::cache::CachedRecord { target: "2001:db8::1".parse::<std::net::IpAddr>().unwrap(), confirmed_at: std::time::UNIX_EPOCH })?;
+        let service = crate::api::Service::builder().zone("invalid").cache(cache).build()?;
+
+        let mut state = crate::state::State::capture(&service)?;
+        assert_eq!(state.records.len(), 2);
+        state.set_token("dual", "INSERT_TOKEN_HERE");
+        state.set_label("dual", "env", "prod");
+        assert_eq!(state.records.len(), 2, "setting the token added a record");
+        assert_eq!(state.select("env", "prod").records.len(), 2);
+
+        let plan = crate::sync::plan(&service, &state.desired());
+        assert_eq!(plan.records.len(), 2);
+        assert!(plan.records.iter().all(|planned| planned.change != crate::sync::Change::Register), "an existing record was planned for registration");
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn dual_stack_registration_keeps_the_token_when_aaaa_fails() -> Result<(), crate::error::DnsApiError> {
+        let url = serve_sequence(&[
+            r#"{"code": 0, "data": "127.0.0.1", "token": "0123456789abcdef0123456789abcdef"}"#,
+            r#"{"code": 0, "data": "::1"}"#,
+            r#"{"code": 0, "data": "127.0.0.1", "token": "0123456789abcdef0123456789abcdef"}"#,
+            r#"{"code": 1, "data": "invalid address"}"#
+        ]);
+        let service = crate::api::Service::builder().allow_private_targets(true).endpoint(&url).build()?;
+
+        let (registered, aaaa) = service.register_dual_stack("dual", std::net::Ipv4Addr::LOCALHOST, std::net::Ipv6Addr::LOCALHOST)?;
+        assert_eq!(registered.token, "0123456789abcdef0123456789abcdef");
+        assert_eq!(aaaa?.target, std::net::IpAddr::from(std::net::Ipv6Addr::LOCALHOST));
+
+        let (registered, aaaa) = service.register_dual_stack("dual", std::net::Ipv4Addr::LOCALHOST, std::net::Ipv6Addr::LOCALHOST)?;
+        assert_eq!(registered.token, "0123456789abcdef0123456789abcdef", "the token was lost");
+        assert!(matches!(aaaa.map_err(crate::error::DnsApiError::into_inner), Err(crate::error::DnsApiError::BadRequest(_))));
+
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,114 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// An exclusive lock that keeps a second instance from working on the same files
+///
+/// The lock is advisory: it is held on the lock file for as long as the guard lives and is released
+/// when it is dropped or the process exits, so a crashed instance never leaves a stale lock behind.
+/// The holder writes its process id into the file, so it doubles as a PID file.
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+    path: PathBuf
+}
+
+impl InstanceLock {
+    /// Takes the lock on `path`, creating the file if needed, and writes this process's id into it
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `std::io::ErrorKind::WouldBlock` if another instance holds the lock,
+    /// naming its process id when known.
+    pub fn acquire<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let mut file = OpenOptions::new().create(true).truncate(false).write(true).open(&path)?;
+
+        match file.try_lock() {
+            Ok(()) => {
+                file.set_len(0)?;
+                writeln!(file, "{}", std::process::id())?;
+                Ok(InstanceLock { _file: file, path })
+            },
+            Err(TryLockError::WouldBlock) => {
+                let holder = match InstanceLock::holder(&path) {
+                    Ok(Some(pid)) => format!(" (pid {})", pid),
+                    _ => String::new()
+                };
+                Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("{} is locked by another instance{}", path.display(), holder)
+                ))
+            },
+            Err(TryLockError::Error(err)) => Err(err)
+        }
+    }
+
+    /// Returns the process id written by the last instance that took the lock on `path`
+    ///
+    /// The process may have exited since; the file only shows who holds the lock while one does.
+    pub fn holder<P: AsRef<Path>>(path: P) -> io::Result<Option<u32>> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => Ok(contents.trim().parse().ok()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Returns the path of the lock file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+// Reads a whole file, returning `None` if it does not exist
+//
+// Files written by `update` are replaced by a rename, so a reader sees either the old or the new
+// contents in full without taking a lock.
+pub(crate) fn read(path: &Path) -> io::Result<Option<String>> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err)
+    }
+}
+
+// Replaces a file's contents under an exclusive lock, letting `f` see the current contents first so
+// concurrent writers can merge instead of overwriting each other
+//
+// The lock is held on a `.lock` file next to it, since the file itself is replaced: the new contents are
+// written to a `.tmp` file, synced and renamed over the original, so a crash mid-write leaves the
+// previous contents intact.
+pub(crate) fn update<T>(path: &Path, f: impl FnOnce(&str) -> (String, T)) -> io::Result<T> {
+    let lock = OpenOptions::new().create(true).truncate(false).write(true).open(sibling(path, "lock"))?;
+    lock.lock()?;
+
+    let current = read(path)?.unwrap_or_default();
+    let (contents, value) = f(&current);
+
+    let temp = sibling(path, "tmp");
+    let mut file = File::create(&temp)?;
+    if let Ok(metadata) = std::fs::metadata(path) {
+        file.set_permissions(metadata.permissions())?;
+    }
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&temp, path)?;
+
+    Ok(value)
+}
+
+fn sibling(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+// Appends to a file under an exclusive lock so lines from concurrent writers never interleave
+pub(crate) fn append(path: &Path, contents: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.lock()?;
+
+    file.write_all(contents.as_bytes())
+}
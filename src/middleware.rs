@@ -0,0 +1,67 @@
+use std::fmt;
+use std::sync::Arc;
+
+use reqwest::blocking::{Request, Response};
+
+/// A hook that sees every API request before it is sent and every response received
+///
+/// Middleware registered with `ServiceBuilder::middleware` runs in the order added, for every operation
+/// and for each endpoint tried. Both methods do nothing by default. Any `Fn(&mut Request)` closure is
+/// middleware that only changes requests.
+///
+/// # Examples
+///
+/// ```no_run
+/// use reqwest::header::{HeaderName, HeaderValue};
+///
+/// let service = anondns_api::api::Service::builder()
+///     .middleware(|request: &mut reqwest::blocking::Request| {
+///         request.headers_mut().insert(HeaderName::from_static("x-signature"), HeaderValue::from_static("example"));
+///     })
+///     .build()
+///     .unwrap();
+/// ```
+pub trait Middleware: Send + Sync {
+    /// Inspects or changes a request before it is sent
+    fn on_request(&self, _request: &mut Request) {}
+
+    /// Inspects a response before the service reads it
+    fn on_response(&self, _response: &Response) {}
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&mut Request) + Send + Sync
+{
+    fn on_request(&self, request: &mut Request) {
+        self(request)
+    }
+}
+
+// The registered middleware, in the order it runs
+#[derive(Clone, Default)]
+pub(crate) struct Stack(Vec<Arc<dyn Middleware>>);
+
+impl Stack {
+    pub(crate) fn push(&mut self, middleware: Arc<dyn Middleware>) {
+        self.0.push(middleware);
+    }
+
+    pub(crate) fn on_request(&self, request: &mut Request) {
+        for middleware in &self.0 {
+            middleware.on_request(request);
+        }
+    }
+
+    pub(crate) fn on_response(&self, response: &Response) {
+        for middleware in &self.0 {
+            middleware.on_response(response);
+        }
+    }
+}
+
+impl fmt::Debug for Stack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Stack({} middleware)", self.0.len())
+    }
+}
@@ -0,0 +1,159 @@
+use crate::api::DEFAULT_ZONE;
+use crate::state::State;
+
+/// Reads the anondns.net hosts of a ddclient configuration file into a state
+///
+/// Settings apply to the hosts listed after them, as in ddclient, and the `password` in effect for a
+/// host is taken as its token. Hosts outside anondns.net are skipped, as are hosts without a password.
+/// Targets are left unknown, since ddclient detects them at run time.
+///
+/// # Examples
+///
+/// ```
+/// let config = "protocol=dyndns2\npassword='0123456789abcdef0123456789abcdef'\nhome.anondns.net, other.example.com\n";
+/// let state = anondns_api::migrate::from_ddclient(config);
+///
+/// assert_eq!(state.records.len(), 1);
+/// assert_eq!(state.records[0].name, "home");
+/// ```
+pub fn from_ddclient(config: &str) -> State {
+    from_ddclient_in(config, DEFAULT_ZONE)
+}
+
+/// Like `from_ddclient`, but reads the hosts under `zone` instead of `DEFAULT_ZONE`
+pub fn from_ddclient_in(config: &str, zone: &str) -> State {
+    let mut state = State::new();
+    let mut password: Option<String> = None;
+
+    for line in config.replace("\\\n", " ").lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        for word in line.split(|c: char| c == ',' || c.is_whitespace()).filter(|word| !word.is_empty()) {
+            match word.split_once('=') {
+                Some(("password", value)) => password = Some(unquote(value).to_owned()),
+                Some(_) => {},
+                None => add(&mut state, zone, unquote(word), password.as_deref())
+            }
+        }
+    }
+
+    state
+}
+
+/// Reads the anondns.net hosts of an inadyn configuration file into a state
+///
+/// Every `provider` and `custom` section contributes its `hostname` entries, with the section's
+/// `password` as their token. Hosts outside anondns.net and sections without a password are skipped.
+///
+/// # Examples
+///
+/// ```
+/// let config = r#"
+/// custom anondns {
+///     password = 0123456789abcdef0123456789abcdef
+///     hostname = { "home.anondns.net", "nas.anondns.net" }
+/// }
+/// "#;
+/// let state = anondns_api::migrate::from_inadyn(config);
+///
+/// assert_eq!(state.records.len(), 2);
+/// ```
+pub fn from_inadyn(config: &str) -> State {
+    from_inadyn_in(config, DEFAULT_ZONE)
+}
+
+/// Like `from_inadyn`, but reads the hosts under `zone` instead of `DEFAULT_ZONE`
+pub fn from_inadyn_in(config: &str, zone: &str) -> State {
+    let mut state = State::new();
+    let tokens = tokenize(config);
+    let mut tokens = tokens.iter().map(String::as_str).peekable();
+
+    // Sections are `provider|custom <name> { key = value ... }`, with lists as `{ value, ... }`
+    while let Some(token) = tokens.next() {
+        if token != "provider" && token != "custom" {
+            continue;
+        }
+        if !tokens.by_ref().any(|token| token == "{") {
+            break;
+        }
+
+        let mut password = None;
+        let mut hostnames = Vec::new();
+        while let Some(key) = tokens.next() {
+            if key == "}" {
+                break;
+            }
+            if tokens.peek() != Some(&"=") {
+                continue;
+            }
+            tokens.next();
+
+            let mut values = Vec::new();
+            if tokens.peek() == Some(&"{") {
+                tokens.next();
+                for value in tokens.by_ref() {
+                    match value {
+                        "}" => break,
+                        "," => {},
+                        value => values.push(value)
+                    }
+                }
+            } else if let Some(value) = tokens.next() {
+                values.push(value);
+            }
+
+            match key {
+                "password" => password = values.first().map(|value| value.to_string()),
+                "hostname" => hostnames.extend(values),
+                _ => {}
+            }
+        }
+
+        for hostname in hostnames {
+            add(&mut state, zone, hostname, password.as_deref());
+        }
+    }
+
+    state
+}
+
+fn add(state: &mut State, zone: &str, host: &str, token: Option<&str>) {
+    let zone = zone.trim_end_matches('.');
+    let Some(name) = host.trim_end_matches('.').strip_suffix(zone).and_then(|name| name.strip_suffix('.')) else {
+        return;
+    };
+    let Some(token) = token else {
+        return;
+    };
+
+    state.set_token(name, token);
+}
+
+fn unquote(value: &str) -> &str {
+    value.trim_matches(|c| c == '\'' || c == '"')
+}
+
+// Splits into words, quoted strings without their quotes, and the symbols `{`, `}`, `=` and `,`
+fn tokenize(config: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+
+    for line in config.lines() {
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '#' => break,
+                '{' | '}' | '=' | ',' => tokens.push(c.to_string()),
+                '"' | '\'' => tokens.push(chars.by_ref().take_while(|next| *next != c).collect()),
+                c if c.is_whitespace() => {},
+                c => {
+                    let mut word = c.to_string();
+                    while let Some(next) = chars.next_if(|next| !next.is_whitespace() && !"{}=,#\"'".contains(*next)) {
+                        word.push(next);
+                    }
+                    tokens.push(word);
+                }
+            }
+        }
+    }
+
+    tokens
+}
\ No newline at end of file
@@ -0,0 +1,229 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::api::DEFAULT_ZONE;
+use crate::error;
+use crate::notify::Event;
+use crate::validate;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const KEEP_ALIVE_SECS: u16 = 60;
+
+/// Publishes record events to an MQTT broker
+///
+/// Each event is sent as a JSON object with a `timestamp` in Unix seconds, using a short-lived MQTT 3.1.1
+/// connection and QoS 0. Connections are plain TCP, so use a broker on a trusted network or a local
+/// bridge when credentials are involved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttPublisher {
+    broker: String,
+    topic: String,
+    client_id: String,
+    credentials: Option<(String, String)>,
+    retain: bool,
+    discovery_prefix: String,
+    zone: String
+}
+
+impl MqttPublisher {
+    /// Creates a publisher for `topic` on the broker at `broker`, given as `host:port`
+    pub fn new(broker: &str, topic: &str) -> Self {
+        MqttPublisher {
+            broker: broker.to_owned(),
+            topic: topic.to_owned(),
+            client_id: format!("anondns-api-{}", std::process::id()),
+            credentials: None,
+            retain: false,
+            discovery_prefix: String::from("homeassistant"),
+            zone: String::from(DEFAULT_ZONE)
+        }
+    }
+
+    /// Authenticates with the broker using a username and password
+    pub fn credentials(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some((username.to_owned(), password.to_owned()));
+        self
+    }
+
+    /// Sets the client identifier presented to the broker
+    pub fn client_id(mut self, client_id: &str) -> Self {
+        self.client_id = client_id.to_owned();
+        self
+    }
+
+    /// Asks the broker to retain the last event, so new subscribers immediately see the current state
+    pub fn retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    /// Sets the topic prefix Home Assistant listens on for discovery messages, `homeassistant` by default
+    pub fn discovery_prefix(mut self, prefix: &str) -> Self {
+        self.discovery_prefix = prefix.trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// Sets the parent zone used to name discovered devices, `DEFAULT_ZONE` unless set
+    ///
+    /// Set it to the zone of the service the records belong to, see `Service::zone`.
+    pub fn zone(mut self, zone: &str) -> Self {
+        self.zone = zone.trim_matches('.').to_owned();
+        self
+    }
+
+    /// Publishes an event
+    ///
+    /// # Errors
+    ///
+    /// Returns `DnsApiError::Io(std::io::Error)` if the broker cannot be reached or refuses the connection.
+    pub fn publish(&self, event: &Event) -> Result<(), error::DnsApiError> {
+        let mut payload = serde_json::to_value(event)?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        payload["timestamp"] = timestamp.into();
+
+        self.send(&[publish_packet(&self.topic, payload.to_string().as_bytes(), self.retain)])
+    }
+
+    /// Announces a subdomain to Home Assistant through MQTT discovery
+    ///
+    /// This creates a device for the subdomain with two sensors, its current address and the time it
+    /// last changed, which follow the events published to this publisher's topic. Discovery messages
+    /// are retained, so publishing them once per subdomain is enough, although repeating it after the
+    /// broker loses its retained messages does no harm.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DnsApiError::InvalidSubdomain(String)` for an invalid subdomain, or
+    /// `DnsApiError::Io(std::io::Error)` if the broker cannot be reached or refuses the connection.
+    pub fn publish_discovery(&self, subdomain: &str) -> Result<(), error::DnsApiError> {
+        validate::subdomain(subdomain)?;
+        let packets: Vec<Vec<u8>> = self.discovery_messages(subdomain).iter()
+            .map(|(topic, config)| publish_packet(topic, config.to_string().as_bytes(), true))
+            .collect();
+
+        self.send(&packets)
+    }
+
+    // Events of every subdomain share one topic, so each sensor keeps its state for other subdomains'
+    // events. Validated subdomains only contain characters that are safe inside the template's quotes.
+    // Home Assistant object ids only allow `[A-Za-z0-9_-]`, so the dots and wildcards of a subdomain
+    // become underscores in the id.
+    pub(crate) fn discovery_messages(&self, subdomain: &str) -> Vec<(String, serde_json::Value)> {
+        let object_id: String = subdomain.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+            .collect();
+        let id = format!("anondns_{}", object_id);
+        let device = serde_json::json!({
+            "identifiers": [id],
+            "name": format!("{}.{}", subdomain, self.zone),
+            "manufacturer": "AnonDNS"
+        });
+        let template = |value: &str| format!(
+            "{{% if value_json.event == 'changed' and value_json.subdomain == '{}' %}}{}{{% else %}}{{{{ this.state }}}}{{% endif %}}",
+            subdomain,
+            value
+        );
+
+        let address = serde_json::json!({
+            "name": "Address",
+            "unique_id": format!("{}_address", id),
+            "state_topic": self.topic,
+            "value_template": template("{{ value_json.new }}"),
+            "icon": "mdi:ip-network",
+            "device": device
+        });
+        let changed = serde_json::json!({
+            "name": "Last changed",
+            "unique_id": format!("{}_changed", id),
+            "state_topic": self.topic,
+            "value_template": template("{{ as_datetime(value_json.timestamp) }}"),
+            "device_class": "timestamp",
+            "device": device
+        });
+
+        vec![
+            (format!("{}/sensor/{}_address/config", self.discovery_prefix, id), address),
+            (format!("{}/sensor/{}_changed/config", self.discovery_prefix, id), changed)
+        ]
+    }
+
+    // Connects, sends the given PUBLISH packets and disconnects
+    fn send(&self, packets: &[Vec<u8>]) -> Result<(), error::DnsApiError> {
+        let addr = self.broker.to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "broker address did not resolve"))?;
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        stream.write_all(&self.connect_packet())?;
+        let mut connack = [0; 4];
+        stream.read_exact(&mut connack)?;
+        if connack[0] != 0x20 || connack[3] != 0 {
+            return Err(io::Error::other(format!("broker refused the connection (code {})", connack[3])).into());
+        }
+
+        for packet in packets {
+            stream.write_all(packet)?;
+        }
+        stream.write_all(&[0xE0, 0x00])?;
+
+        Ok(())
+    }
+
+    fn connect_packet(&self) -> Vec<u8> {
+        let mut flags = 0x02;
+        let mut body = Vec::new();
+        encode_str(&mut body, "MQTT");
+        body.push(4);
+
+        let mut payload = Vec::new();
+        encode_str(&mut payload, &self.client_id);
+        if let Some((username, password)) = &self.credentials {
+            flags |= 0xC0;
+            encode_str(&mut payload, username);
+            encode_str(&mut payload, password);
+        }
+
+        body.push(flags);
+        body.extend_from_slice(&KEEP_ALIVE_SECS.to_be_bytes());
+        body.extend(payload);
+
+        packet(0x10, body)
+    }
+}
+
+pub(crate) fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    encode_str(&mut body, topic);
+    body.extend_from_slice(payload);
+
+    packet(0x30 | retain as u8, body)
+}
+
+fn packet(header: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+
+    // Remaining length is a variable-length integer of 7 bits per byte
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+
+    packet.extend(body);
+    packet
+}
+
+fn encode_str(buf: &mut Vec<u8>, value: &str) {
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value.as_bytes());
+}
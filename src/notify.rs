@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::api::{IpAddr, UpdateOutcome};
+use crate::error;
+
+/// Something worth telling a human about
+#[derive(serde_derive::Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A record now points somewhere else; `old` is `None` if the previous target was not known
+    Changed { subdomain: String, old: Option<IpAddr>, new: IpAddr },
+    /// Updates of a record have failed `failures` times in a row, starting at `since`
+    Failing { subdomain: String, failures: u32, since: SystemTime, error: String }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Changed { subdomain, old: Some(old), new } => write!(f, "{} changed from {} to {}", subdomain, old, new),
+            Event::Changed { subdomain, old: None, new } => write!(f, "{} now points to {}", subdomain, new),
+            Event::Failing { subdomain, failures, since, error } => {
+                let minutes = since.elapsed().unwrap_or_default().as_secs() / 60;
+                write!(f, "updating {} failed {} times in a row over {} minutes: {}", subdomain, failures, minutes, error)
+            }
+        }
+    }
+}
+
+/// Where notifications are delivered
+///
+/// Some channels only exist with a feature enabled, so matches on it need a wildcard arm.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Channel {
+    /// POSTs the event as JSON to the URL
+    Webhook(String),
+    /// Posts the message to a Discord webhook URL
+    Discord(String),
+    /// Posts the message to a Slack incoming webhook URL
+    Slack(String),
+    /// Publishes the event as JSON to an MQTT topic
+    #[cfg(feature = "mqtt")]
+    Mqtt(crate::mqtt::MqttPublisher)
+}
+
+/// Sends notifications when records change or keep failing to update
+///
+/// Feed it the result of every update with `observe`. A change is reported when the outcome's target
+/// differs from its previous one, so attach a `RecordCache` to the service to avoid reporting every
+/// update as a change. Failures are reported once, when they reach the configured number in a row, and
+/// then at most once per reminder interval if one is set, for as long as the record keeps failing.
+#[derive(Debug)]
+pub struct Notifier {
+    client: reqwest::blocking::Client,
+    channels: Vec<Channel>,
+    subdomain_channels: HashMap<String, Vec<Channel>>,
+    failure_threshold: u32,
+    reminder_interval: Option<Duration>,
+    failures: Mutex<HashMap<String, Failures>>
+}
+
+// The current run of failures of one subdomain
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    since: SystemTime,
+    notified_at: Option<Instant>
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Notifier::new()
+    }
+}
+
+impl Notifier {
+    /// Creates a notifier with no channels that reports failures after 3 in a row
+    pub fn new() -> Self {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("failed to initialize reqwest blocking client");
+
+        Notifier {
+            client,
+            channels: Vec::new(),
+            subdomain_channels: HashMap::new(),
+            failure_threshold: 3,
+            reminder_interval: None,
+            failures: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Adds a channel used for every subdomain without channels of its own
+    pub fn channel(mut self, channel: Channel) -> Self {
+        self.channels.push(channel);
+        self
+    }
+
+    /// Adds a channel used only for the given subdomain, replacing the default channels for it
+    pub fn subdomain_channel(mut self, subdomain: &str, channel: Channel) -> Self {
+        self.subdomain_channels.entry(subdomain.to_owned()).or_default().push(channel);
+        self
+    }
+
+    /// Sets how many failures in a row trigger a `Failing` notification
+    pub fn failure_threshold(mut self, threshold: u32) -> Self {
+        self.failure_threshold = threshold.max(1);
+        self
+    }
+
+    /// Repeats the `Failing` notification of a record that keeps failing at most once per `interval`
+    pub fn reminder_interval(mut self, interval: Duration) -> Self {
+        self.reminder_interval = Some(interval);
+        self
+    }
+
+    /// Records the result of an update and sends any resulting notification
+    ///
+    /// Returns the delivery result of each channel notified, which is empty if nothing was worth reporting.
+    pub fn observe(&self, subdomain: &str, result: &Result<UpdateOutcome, error::DnsApiError>) -> Vec<Result<(), error::DnsApiError>> {
+        let mut failures = self.failures.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let event = match result {
+            Ok(outcome) => {
+                failures.remove(subdomain);
+                if outcome.previous == Some(outcome.target) {
+                    return Vec::new();
+                }
+                Event::Changed { subdomain: subdomain.to_owned(), old: outcome.previous, new: outcome.target }
+            },
+            Err(err) => {
+                let run = failures.entry(subdomain.to_owned())
+                    .or_insert_with(|| Failures { count: 0, since: SystemTime::now(), notified_at: None });
+                run.count += 1;
+
+                let due = match (run.notified_at, self.reminder_interval) {
+                    (None, _) => run.count >= self.failure_threshold,
+                    (Some(at), Some(interval)) => at.elapsed() >= interval,
+                    (Some(_), None) => false
+                };
+                if !due {
+                    return Vec::new();
+                }
+                run.notified_at = Some(Instant::now());
+                Event::Failing { subdomain: subdomain.to_owned(), failures: run.count, since: run.since, error: err.to_string() }
+            }
+        };
+        drop(failures);
+
+        self.notify(subdomain, &event)
+    }
+
+    /// Sends an event to the channels configured for a subdomain
+    pub fn notify(&self, subdomain: &str, event: &Event) -> Vec<Result<(), error::DnsApiError>> {
+        let channels = self.subdomain_channels.get(subdomain).unwrap_or(&self.channels);
+
+        channels.iter()
+            .map(|channel| self.send(channel, event))
+            .collect()
+    }
+
+    fn send(&self, channel: &Channel, event: &Event) -> Result<(), error::DnsApiError> {
+        let request = match channel {
+            #[cfg(feature = "mqtt")]
+            Channel::Mqtt(publisher) => return publisher.publish(event),
+            Channel::Webhook(url) => self.client.post(url).json(event),
+            Channel::Discord(url) => self.client.post(url).json(&serde_json::json!({ "content": event.to_string() })),
+            Channel::Slack(url) => self.client.post(url).json(&serde_json::json!({ "text": event.to_string() }))
+        };
+        request.send()?.error_for_status()?;
+
+        Ok(())
+    }
+}
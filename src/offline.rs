@@ -0,0 +1,153 @@
+use std::collections::VecDeque;
+use std::io;
+use std::path::PathBuf;
+
+use crate::api::{IpAddr, RegisterOutcome, Service, UpdateOutcome};
+use crate::error;
+use crate::lock;
+
+/// An operation that could not reach the API and is waiting to be replayed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    Register { subdomain: String, target: IpAddr },
+    Update { subdomain: String, target: IpAddr, token: String }
+}
+
+/// The successful result of a replayed `Operation`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Replayed {
+    Registered(RegisterOutcome),
+    Updated(UpdateOutcome)
+}
+
+impl Operation {
+    fn run(&self, service: &Service) -> Result<Replayed, error::DnsApiError> {
+        match self {
+            Operation::Register { subdomain, target } => service.register(subdomain, *target).map(Replayed::Registered),
+            Operation::Update { subdomain, target, token } => service.update(subdomain, *target, token).map(Replayed::Updated)
+        }
+    }
+
+    fn to_line(&self) -> String {
+        match self {
+            Operation::Register { subdomain, target } => format!("register\t{}\t{}", subdomain, target),
+            Operation::Update { subdomain, target, token } => format!("update\t{}\t{}\t{}", subdomain, target, token)
+        }
+    }
+
+    fn from_line(line: &str) -> Option<Operation> {
+        let fields: Vec<&str> = line.split('\t').collect();
+
+        match fields.as_slice() {
+            ["register", subdomain, target] => Some(Operation::Register {
+                subdomain: subdomain.to_string(),
+                target: target.parse().ok()?
+            }),
+            ["update", subdomain, target, token] => Some(Operation::Update {
+                subdomain: subdomain.to_string(),
+                target: target.parse().ok()?,
+                token: token.to_string()
+            }),
+            _ => None
+        }
+    }
+}
+
+/// A small on-disk queue of operations that failed because the API was unreachable or rate limited them
+///
+/// Operations are stored one per line, in the order they were queued, and are replayed in that same
+/// order. Queued updates include their token in plain text, so the file should be kept somewhere only
+/// the owning user can read. Writes are locked and replace the file atomically, but the queue keeps its
+/// contents in memory, so only one process should use a given queue file at a time.
+#[derive(Debug)]
+pub struct OfflineQueue {
+    path: PathBuf,
+    operations: VecDeque<Operation>
+}
+
+impl OfflineQueue {
+    /// Opens the queue stored at `path`, loading any operations left over from a previous run
+    ///
+    /// A missing file is treated as an empty queue and is only created once an operation is queued.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let operations = lock::read(&path)?
+            .map(|contents| contents.lines().filter_map(Operation::from_line).collect())
+            .unwrap_or_default();
+
+        Ok(OfflineQueue { path, operations })
+    }
+
+    /// Returns the operations currently waiting to be replayed, oldest first
+    pub fn pending(&self) -> impl Iterator<Item = &Operation> {
+        self.operations.iter()
+    }
+
+    /// Returns `true` if no operations are waiting to be replayed
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// Appends an operation to the queue and persists it
+    pub fn push(&mut self, operation: Operation) -> io::Result<()> {
+        self.operations.push_back(operation);
+        self.save()
+    }
+
+    /// Runs an operation, queueing it for later if the API cannot be reached
+    ///
+    /// Returns `Ok(None)` if the operation was queued instead of completed. Operations are queued without
+    /// being attempted while older ones are still pending, so call `replay` first to keep them in order.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the attempted operation if it was rejected for any reason other than the API
+    /// being unreachable or rate limiting it, see `DnsApiError::is_retryable`. Failing to persist the queue is reported as `DnsApiError::Io(std::io::Error)`.
+    pub fn run(&mut self, service: &Service, operation: Operation) -> Result<Option<Replayed>, error::DnsApiError> {
+        if !self.is_empty() {
+            self.push(operation)?;
+            return Ok(None);
+        }
+
+        match operation.run(service) {
+            Ok(replayed) => Ok(Some(replayed)),
+            Err(err) if err.is_retryable() => {
+                self.push(operation)?;
+                Ok(None)
+            },
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Replays queued operations in order and returns the result of each one that was attempted
+    ///
+    /// Replaying stops at the first operation that still cannot reach the API or is rate limited; it and
+    /// everything after it stay queued. Operations rejected by the API are dropped from the queue and reported.
+    pub fn replay(&mut self, service: &Service) -> io::Result<Vec<(Operation, Result<Replayed, error::DnsApiError>)>> {
+        let mut results = Vec::new();
+
+        while let Some(operation) = self.operations.front() {
+            let result = operation.run(service);
+            if matches!(&result, Err(err) if err.is_retryable()) {
+                break;
+            }
+
+            let operation = self.operations.pop_front().unwrap();
+            results.push((operation, result));
+        }
+
+        if !results.is_empty() {
+            self.save()?;
+        }
+
+        Ok(results)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let contents: String = self.operations.iter()
+            .map(|op| op.to_line() + "\n")
+            .collect();
+
+        lock::update(&self.path, |_| (contents, ()))
+    }
+}
@@ -0,0 +1,5 @@
+//! The types most programs need, for glob importing with `use anondns_api::prelude::*`
+
+pub use crate::api::{IpAddr, Ipv4Addr, Ipv6Addr, Record, RegisterOutcome, Service, ServiceBuilder, Token, UpdateOutcome};
+pub use crate::error::DnsApiError;
+pub use crate::ext::{RecordResultExt, RegisterResultExt};
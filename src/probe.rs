@@ -0,0 +1,148 @@
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use crate::api::{IpAddr, Service, UpdateOutcome};
+#[cfg(feature = "failover")]
+use crate::api::Token;
+use crate::error;
+
+/// How to tell whether a target is serving traffic
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Probe {
+    /// The target accepts a TCP connection on `port`
+    Tcp { port: u16 },
+    /// The target answers `GET http://<target>:<port><path>` with a success or redirect status
+    Http { port: u16, path: String }
+}
+
+impl Probe {
+    /// Returns `true` if the target passes the probe within `timeout`
+    pub fn is_healthy(&self, target: IpAddr, timeout: Duration) -> bool {
+        match self {
+            Probe::Tcp { port } => TcpStream::connect_timeout(&SocketAddr::new(target, *port), timeout).is_ok(),
+            Probe::Http { port, path } => {
+                let url = format!("http://{}{}", SocketAddr::new(target, *port), path);
+                let client = reqwest::blocking::Client::builder()
+                    .timeout(timeout)
+                    .redirect(reqwest::redirect::Policy::none())
+                    .build();
+
+                client.and_then(|client| client.get(url).send())
+                    .is_ok_and(|response| response.status().is_success() || response.status().is_redirection())
+            }
+        }
+    }
+}
+
+/// Returns `true` if `target` accepts a TCP connection on `port` within `timeout`
+pub fn probe_target(target: impl Into<IpAddr>, port: u16, timeout: Duration) -> bool {
+    Probe::Tcp { port }.is_healthy(target.into(), timeout)
+}
+
+/// Updates the subdomain to `target` only if the target passes `probe` first, so a dead address is
+/// never published
+///
+/// # Errors
+///
+/// Returns `DnsApiError::NoHealthyTarget` without sending anything if the probe fails, or any error of
+/// `Service::update`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use anondns_api::probe::{update_if_healthy, Probe};
+///
+/// let service = anondns_api::api::Service::new();
+/// let probe = Probe::Http { port: 80, path: String::from("/health") };
+/// let target = std::net::Ipv4Addr::new(203, 0, 113, 1);
+/// update_if_healthy(&service, "example_subdomain", target, "example_token", &probe, Duration::from_secs(5)).unwrap();
+/// ```
+pub fn update_if_healthy(service: &Service, subdomain: &str, target: impl Into<IpAddr>, token: impl AsRef<str>, probe: &Probe, timeout: Duration) -> Result<UpdateOutcome, error::DnsApiError> {
+    let target = target.into();
+    if !probe.is_healthy(target, timeout) {
+        return Err(error::DnsApiError::NoHealthyTarget);
+    }
+
+    service.update(subdomain, target, token)
+}
+
+/// A record that points at the first healthy target out of a primary and ordered fallbacks
+///
+/// Call `check` periodically: it probes the targets in order and repoints the record when the first
+/// healthy one is not the one it currently points to. Because the primary is always probed first, the
+/// record fails back to it as soon as it recovers.
+///
+/// Requires the `failover` feature; the probes and `update_if_healthy` are always available.
+#[cfg(feature = "failover")]
+#[derive(Debug, Clone)]
+pub struct FailoverRecord {
+    name: String,
+    token: Token,
+    targets: Vec<IpAddr>,
+    probe: Probe,
+    timeout: Duration,
+    active: Option<IpAddr>
+}
+
+#[cfg(feature = "failover")]
+impl FailoverRecord {
+    /// Creates a record for `name`, authorized by `token`, whose preferred target is `primary`
+    ///
+    /// Targets are probed with a TCP connection to port 80 and a 5 second timeout unless configured.
+    pub fn new(name: &str, token: impl Into<Token>, primary: impl Into<IpAddr>) -> Self {
+        FailoverRecord {
+            name: name.to_owned(),
+            token: token.into(),
+            targets: vec![primary.into()],
+            probe: Probe::Tcp { port: 80 },
+            timeout: Duration::from_secs(5),
+            active: None
+        }
+    }
+
+    /// Adds a fallback target, tried after the primary and every fallback added before it
+    pub fn fallback(mut self, target: impl Into<IpAddr>) -> Self {
+        self.targets.push(target.into());
+        self
+    }
+
+    /// Sets the probe used to check targets
+    pub fn probe(mut self, probe: Probe) -> Self {
+        self.probe = probe;
+        self
+    }
+
+    /// Sets how long each probe may take
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the target the record was last pointed to by `check`
+    pub fn active(&self) -> Option<IpAddr> {
+        self.active
+    }
+
+    /// Probes the targets and repoints the record if needed, returning the outcome if it was updated
+    ///
+    /// # Errors
+    ///
+    /// Returns `DnsApiError::NoHealthyTarget` if every target failed its probe, in which case the record
+    /// is left pointing where it was, or any error of `Service::update`.
+    pub fn check(&mut self, service: &Service) -> Result<Option<UpdateOutcome>, error::DnsApiError> {
+        let healthy = self.targets.iter()
+            .copied()
+            .find(|target| self.probe.is_healthy(*target, self.timeout))
+            .ok_or(error::DnsApiError::NoHealthyTarget)?;
+
+        if self.active == Some(healthy) {
+            return Ok(None);
+        }
+
+        let outcome = service.update(&self.name, healthy, &self.token)?;
+        self.active = Some(outcome.target);
+
+        Ok(Some(outcome))
+    }
+}
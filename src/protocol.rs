@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::api::{ApiVersion, IpAddr, DEFAULT_ZONE};
+use crate::error;
+
+// How many characters of an unexpected page are kept for the error message
+const SNIPPET_LEN: usize = 120;
+
+/// A request to the API, described without sending it
+///
+/// Every call is a `GET` of `path` on an endpoint, with no body, so any HTTP client can send it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// The path and its parameters, starting with `/`. Update paths contain the token.
+    pub path: String
+}
+
+impl Request {
+    /// Returns the full URL of the request on `endpoint`, given without a trailing slash
+    pub fn url(&self, endpoint: &str) -> String {
+        format!("{}{}", endpoint, self.path)
+    }
+}
+
+/// A decoded API response
+///
+/// `code` is 0 on success and 1 for a rejected request, in which case `data` holds the reason. On a
+/// successful update `data` holds the confirmed target. The code is accepted as a number or a numeric
+/// string, and a response without one is taken as successful so that `data` decides. A service in
+/// strict mode rejects both: it requires the code to be present and a JSON integer.
+#[derive(serde_derive::Deserialize, Debug, Clone, PartialEq)]
+pub struct Response {
+    #[serde(default, deserialize_with = "lenient_code")]
+    pub code: i32,
+    pub data: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub status: Option<i32>,
+    #[serde(default, rename = "type")]
+    pub record_type: Option<String>,
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub updated: Option<String>,
+    /// Fields this crate does not know about
+    #[serde(flatten)]
+    pub unknown: HashMap<String, serde_json::Value>
+}
+
+impl Response {
+    /// Converts a non-zero `code` into the matching error
+    ///
+    /// # Errors
+    ///
+    /// This function may return one of the following error variants:
+    /// - `DnsApiError::BadRequest((i32, String))`
+    /// - `DnsApiError::UnknownErrorCode((i32, String))`
+    pub fn into_result(self) -> Result<Response, error::DnsApiError> {
+        match self.code {
+            0 => Ok(self),
+            1 => Err(error::DnsApiError::BadRequest((1, self.data))),
+            v => Err(error::DnsApiError::UnknownErrorCode((v, self.data)))
+        }
+    }
+
+    /// Returns the names of the unknown fields, sorted
+    pub fn unknown_fields(&self) -> Vec<&str> {
+        let mut fields: Vec<&str> = self.unknown.keys().map(String::as_str).collect();
+        fields.sort_unstable();
+        fields
+    }
+}
+
+/// An API operation, which decides the path of its request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation<'a> {
+    Register { subdomain: &'a str, target: IpAddr },
+    Update { subdomain: &'a str, token: &'a str, target: IpAddr }
+}
+
+impl Operation<'_> {
+    /// Describes the request that performs the operation
+    ///
+    /// Every path segment is percent-encoded, so no subdomain or token can add segments or a query to
+    /// the path. Wildcards are encoded too, so no proxy or server along the way treats the `*` as
+    /// anything special.
+    pub fn request(&self, version: ApiVersion) -> Request {
+        self.request_in(version, DEFAULT_ZONE)
+    }
+
+    /// Describes the request that performs the operation on a record under `zone` instead of `DEFAULT_ZONE`
+    pub fn request_in(&self, version: ApiVersion, zone: &str) -> Request {
+        let segments: Vec<String> = match (version, self) {
+            (ApiVersion::V1, Operation::Register { subdomain, target }) => vec![
+                String::from("api"),
+                String::from("register"),
+                format!("{}.{}", subdomain, zone),
+                record_type(target).to_owned(),
+                target.to_string()
+            ],
+            (ApiVersion::V1, Operation::Update { subdomain, token, target }) => vec![
+                String::from("api"),
+                String::from("set"),
+                format!("{}.{}", subdomain, zone),
+                token.to_string(),
+                record_type(target).to_owned(),
+                target.to_string()
+            ]
+        };
+        let segments: Vec<String> = segments.iter().map(|segment| encode_segment(segment)).collect();
+
+        Request { path: format!("/{}", segments.join("/")) }
+    }
+}
+
+fn lenient_code<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<i32, D::Error> {
+    struct CodeVisitor;
+
+    impl serde::de::Visitor<'_> for CodeVisitor {
+        type Value = i32;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("an integer or a string holding one")
+        }
+
+        fn visit_i64<E: serde::de::Error>(self, value: i64) -> Result<i32, E> {
+            i32::try_from(value).map_err(|_| E::invalid_value(serde::de::Unexpected::Signed(value), &self))
+        }
+
+        fn visit_u64<E: serde::de::Error>(self, value: u64) -> Result<i32, E> {
+            i32::try_from(value).map_err(|_| E::invalid_value(serde::de::Unexpected::Unsigned(value), &self))
+        }
+
+        fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<i32, E> {
+            value.trim().parse().map_err(|_| E::invalid_value(serde::de::Unexpected::Str(value), &self))
+        }
+    }
+
+    deserializer.deserialize_any(CodeVisitor)
+}
+
+// Strict mode only accepts the code as the API documents it, a JSON integer, so a missing one fails too
+pub(crate) fn has_integer_code(body: &[u8]) -> bool {
+    serde_json::from_slice::<serde_json::Value>(body).is_ok_and(|value| value["code"].is_i64())
+}
+
+/// Describes the request that registers `subdomain` to `target`
+pub fn register(version: ApiVersion, subdomain: &str, target: IpAddr) -> Request {
+    Operation::Register { subdomain, target }.request(version)
+}
+
+/// Describes the request that points `subdomain` at `target`, authorized by `token`
+pub fn update(version: ApiVersion, subdomain: &str, token: &str, target: IpAddr) -> Request {
+    Operation::Update { subdomain, token, target }.request(version)
+}
+
+// Keeps unreserved characters and the colons of IPv6 addresses, which are valid in a path segment
+fn encode_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b':' => encoded.push(byte as char),
+            byte => encoded.push_str(&format!("%{:02X}", byte))
+        }
+    }
+    encoded
+}
+
+/// Decodes a response body
+///
+/// # Errors
+///
+/// Returns `DnsApiError::Json(serde_json::Error)` if the body is not a valid response.
+///
+/// # Examples
+///
+/// ```
+/// let response = anondns_api::protocol::parse(br#"{"code":0,"data":"203.0.113.1"}"#).unwrap();
+/// assert_eq!(response.data, "203.0.113.1");
+/// ```
+pub fn parse(body: &[u8]) -> Result<Response, error::DnsApiError> {
+    Ok(serde_json::from_slice(body)?)
+}
+
+/// Decodes a response body, recognising pages served in place of the API
+///
+/// `content_type` is the value of the response's `Content-Type` header, if it had one.
+///
+/// # Errors
+///
+/// Returns `DnsApiError::ServiceUnavailable(String)` with the start of the page if the body is not JSON
+/// and either the content type is not JSON or the body looks like HTML, such as a captive portal or
+/// maintenance page. Returns `DnsApiError::Json(serde_json::Error)` for other invalid bodies.
+pub fn parse_with_content_type(content_type: Option<&str>, body: &[u8]) -> Result<Response, error::DnsApiError> {
+    let err = match parse(body) {
+        Ok(response) => return Ok(response),
+        Err(err) => err
+    };
+
+    let text = String::from_utf8_lossy(body);
+    let is_json = content_type.is_none_or(|content_type| content_type.to_ascii_lowercase().contains("json"));
+    if is_json && !text.trim_start().starts_with('<') {
+        return Err(err);
+    }
+
+    let mut snippet: String = text.split_whitespace().collect::<Vec<_>>().join(" ").chars().take(SNIPPET_LEN).collect();
+    if snippet.is_empty() {
+        snippet = String::from("(empty body)");
+    }
+    Err(error::DnsApiError::ServiceUnavailable(snippet))
+}
+
+/// Returns the record type path segment the API expects for an address, `"a"` or `"aaaa"`
+pub fn record_type(target: &IpAddr) -> &'static str {
+    match target {
+        IpAddr::V4(_) => "a",
+        IpAddr::V6(_) => "aaaa"
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::api::{IpAddr, Service, UpdateOutcome};
+use crate::error;
+use crate::protocol::record_type;
+
+/// How urgently a queued update should be sent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Work nobody is waiting on, sent after everything else that is due
+    ///
+    /// `Bulk`, `Plan::apply` and `Service::renew_due` send their updates directly rather than through a
+    /// queue, so push such work here yourself to have it yield to other updates.
+    Background,
+    #[default]
+    Normal,
+    /// Updates a person is waiting on, sent without waiting for the window and ahead of everything else
+    Urgent
+}
+
+#[derive(Debug, Clone)]
+struct Pending {
+    target: IpAddr,
+    token: String,
+    since: Instant,
+    // When the pending target last changed, which damping measures stability from
+    changed_at: Instant,
+    priority: Priority
+}
+
+impl Pending {
+    fn wait(&self, window: Duration, damping: Option<Duration>) -> Duration {
+        if self.priority == Priority::Urgent {
+            return Duration::ZERO;
+        }
+
+        let wait = window.saturating_sub(self.since.elapsed());
+        match damping {
+            Some(damping) => wait.max(damping.saturating_sub(self.changed_at.elapsed())),
+            None => wait
+        }
+    }
+}
+
+/// Coalesces rapid update requests for the same record into a single API call
+///
+/// Updates are kept per subdomain and record type, so an IPv4 and an IPv6 update for the same subdomain
+/// are both sent. The first update pushed for a record opens a window of the configured length. Any
+/// further updates pushed for that record before the window closes replace the pending target, so only
+/// the latest one is sent once the window has elapsed.
+///
+/// Each update has a `Priority`. Urgent updates are due as soon as they are pushed, and flushes send
+/// due updates in priority order, so an urgent update is never stuck behind a large batch of
+/// background ones.
+///
+/// With damping enabled, an update is also held until its target has stayed the same for the damping
+/// period, and dropped if the target flaps back to the one last sent before that, so a link that
+/// briefly changes address does not cause two updates.
+#[derive(Debug, Clone)]
+pub struct UpdateQueue {
+    window: Duration,
+    damping: Option<Duration>,
+    pending: HashMap<Key, Pending>,
+    sent: HashMap<Key, IpAddr>
+}
+
+// A subdomain and the record type of its target
+type Key = (String, &'static str);
+
+impl UpdateQueue {
+    /// Creates an empty queue that coalesces updates arriving within `window` of each other
+    pub fn new(window: Duration) -> Self {
+        UpdateQueue {
+            window,
+            damping: None,
+            pending: HashMap::new(),
+            sent: HashMap::new()
+        }
+    }
+
+    /// Holds each update until its target has been stable for `period`
+    ///
+    /// Urgent updates are still sent as soon as they are pushed.
+    pub fn damping(mut self, period: Duration) -> Self {
+        self.damping = Some(period);
+        self
+    }
+
+    /// Queues an update, replacing any target still pending for the same subdomain and record type
+    ///
+    /// # Arguments
+    ///
+    /// * `subdomain` - A string slice that holds the DNS subdomain to update
+    /// * `target` - The new address the subdomain will redirect to
+    /// * `token` - The token returned when the subdomain was registered
+    pub fn push(&mut self, subdomain: &str, target: impl Into<IpAddr>, token: String) {
+        self.push_with_priority(subdomain, target, token, Priority::Normal);
+    }
+
+    /// Queues an update with the given priority
+    ///
+    /// An update replacing a pending one keeps the higher of the two priorities.
+    pub fn push_with_priority(&mut self, subdomain: &str, target: impl Into<IpAddr>, token: String, priority: Priority) {
+        let target = target.into();
+        let key = (subdomain.to_owned(), record_type(&target));
+        let now = Instant::now();
+        if self.damping.is_some() && priority != Priority::Urgent && self.sent.get(&key) == Some(&target) {
+            self.pending.remove(&key);
+            return;
+        }
+
+        let (since, changed_at, priority) = match self.pending.get(&key) {
+            Some(p) => (p.since, if p.target == target { p.changed_at } else { now }, p.priority.max(priority)),
+            None => (now, now, priority)
+        };
+
+        self.pending.insert(key, Pending { target, token, since, changed_at, priority });
+    }
+
+    /// Returns the number of records with a pending update
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns `true` if no updates are pending
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Returns the time left until the next pending update is due, or `None` if the queue is empty
+    pub fn next_due(&self) -> Option<Duration> {
+        self.pending.values()
+            .map(|p| p.wait(self.window, self.damping))
+            .min()
+    }
+
+    /// Sends every pending update that is due and returns the result for each subdomain
+    pub fn flush_due(&mut self, service: &Service) -> Vec<(String, Result<UpdateOutcome, error::DnsApiError>)> {
+        let due: Vec<Key> = self.pending.iter()
+            .filter(|(_, p)| p.wait(self.window, self.damping).is_zero())
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        self.send(service, due)
+    }
+
+    /// Sends every pending update immediately, regardless of its window
+    pub fn flush(&mut self, service: &Service) -> Vec<(String, Result<UpdateOutcome, error::DnsApiError>)> {
+        let all: Vec<Key> = self.pending.keys().cloned().collect();
+
+        self.send(service, all)
+    }
+
+    fn send(&mut self, service: &Service, keys: Vec<Key>) -> Vec<(String, Result<UpdateOutcome, error::DnsApiError>)> {
+        let mut due: Vec<(Key, Pending)> = keys.into_iter()
+            .filter_map(|key| self.pending.remove(&key).map(|p| (key, p)))
+            .collect();
+        due.sort_by_key(|(_, p)| std::cmp::Reverse(p.priority));
+
+        due.into_iter()
+            .map(|(key, p)| {
+                let result = service.update(&key.0, p.target, &p.token);
+                if let Ok(outcome) = &result {
+                    self.sent.insert((key.0.clone(), record_type(&outcome.target)), outcome.target);
+                }
+                (key.0, result)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,169 @@
+use std::net::{IpAddr, ToSocketAddrs};
+#[cfg(feature = "dot")]
+use std::io::{self, Read, Write};
+#[cfg(feature = "dot")]
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+#[cfg(feature = "dot")]
+use std::time::Duration;
+
+use crate::error;
+
+#[cfg(feature = "dot")]
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[cfg(feature = "dot")]
+const TYPE_A: u16 = 1;
+#[cfg(feature = "dot")]
+const TYPE_AAAA: u16 = 28;
+
+/// How names are resolved when looking up or verifying records
+///
+/// Some resolvers only exist with a feature enabled, so matches on it need a wildcard arm.
+#[non_exhaustive]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Resolver {
+    /// The system resolver, which may answer from a local cache
+    #[default]
+    System,
+    /// A DNS-over-TLS server, such as `1.1.1.1:853` named `cloudflare-dns.com`
+    ///
+    /// Queries go straight to the server over an authenticated connection, so neither local caches nor
+    /// the network in between can alter the answer. `name` is checked against the server's certificate.
+    #[cfg(feature = "dot")]
+    Tls { server: SocketAddr, name: String }
+}
+
+impl Resolver {
+    /// Returns the IPv4 and IPv6 addresses of `host`, sorted and without duplicates
+    ///
+    /// # Errors
+    ///
+    /// Returns `DnsApiError::Io(std::io::Error)` if the name could not be resolved or the resolver could
+    /// not be reached.
+    pub fn lookup(&self, host: &str) -> Result<Vec<IpAddr>, error::DnsApiError> {
+        let mut addresses: Vec<IpAddr> = match self {
+            Resolver::System => (host, 0).to_socket_addrs()?.map(|addr| addr.ip()).collect(),
+            #[cfg(feature = "dot")]
+            Resolver::Tls { server, name } => lookup_tls(*server, name, host)?
+        };
+        addresses.sort();
+        addresses.dedup();
+
+        Ok(addresses)
+    }
+}
+
+#[cfg(feature = "dot")]
+fn lookup_tls(server: SocketAddr, name: &str, host: &str) -> io::Result<Vec<IpAddr>> {
+    let connector = native_tls::TlsConnector::new().map_err(io::Error::other)?;
+    let stream = TcpStream::connect_timeout(&server, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut stream = connector.connect(name, stream).map_err(|err| io::Error::other(err.to_string()))?;
+
+    let mut addresses = Vec::new();
+    for (id, record_type) in [(1, TYPE_A), (2, TYPE_AAAA)] {
+        addresses.extend(exchange(&mut stream, id, host, record_type)?);
+    }
+    Ok(addresses)
+}
+
+// Sends one query over a stream transport, where every message is prefixed with its length
+#[cfg(feature = "dot")]
+fn exchange<S: Read + Write>(stream: &mut S, id: u16, host: &str, record_type: u16) -> io::Result<Vec<IpAddr>> {
+    let query = query(id, host, record_type)?;
+    let mut message = (query.len() as u16).to_be_bytes().to_vec();
+    message.extend(query);
+    stream.write_all(&message)?;
+
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response)?;
+
+    parse_answers(&response, id)
+}
+
+// Builds a recursive query for one record type of `host`
+#[cfg(feature = "dot")]
+pub(crate) fn query(id: u16, host: &str, record_type: u16) -> io::Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(host.len() + 18);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a valid host name", host)));
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&record_type.to_be_bytes());
+    message.extend_from_slice(&[0, 1]);
+
+    Ok(message)
+}
+
+// Returns the A and AAAA records in the answer section of a response to query `id`
+#[cfg(feature = "dot")]
+pub(crate) fn parse_answers(message: &[u8], id: u16) -> io::Result<Vec<IpAddr>> {
+    if u16_at(message, 0)? != id {
+        return Err(invalid("response does not match the query"));
+    }
+    match byte_at(message, 3)? & 0x0F {
+        0 => {},
+        3 => return Err(io::Error::new(io::ErrorKind::NotFound, "name does not exist")),
+        code => return Err(io::Error::other(format!("resolver failed with code {}", code)))
+    }
+
+    let mut pos = 12;
+    for _ in 0..u16_at(message, 4)? {
+        pos = skip_name(message, pos)? + 4;
+    }
+
+    let mut addresses = Vec::new();
+    for _ in 0..u16_at(message, 6)? {
+        pos = skip_name(message, pos)?;
+        let record_type = u16_at(message, pos)?;
+        let len = u16_at(message, pos + 8)? as usize;
+        let data = message.get(pos + 10..pos + 10 + len).ok_or_else(|| invalid("truncated answer"))?;
+
+        match (record_type, <[u8; 4]>::try_from(data), <[u8; 16]>::try_from(data)) {
+            (TYPE_A, Ok(octets), _) => addresses.push(Ipv4Addr::from(octets).into()),
+            (TYPE_AAAA, _, Ok(octets)) => addresses.push(Ipv6Addr::from(octets).into()),
+            _ => {}
+        }
+        pos += 10 + len;
+    }
+
+    Ok(addresses)
+}
+
+// Returns the position after a possibly compressed name
+#[cfg(feature = "dot")]
+fn skip_name(message: &[u8], mut pos: usize) -> io::Result<usize> {
+    loop {
+        let len = byte_at(message, pos)?;
+        match len {
+            0 => return Ok(pos + 1),
+            len if len & 0xC0 == 0xC0 => return Ok(pos + 2),
+            len => pos += 1 + len as usize
+        }
+    }
+}
+
+#[cfg(feature = "dot")]
+fn byte_at(message: &[u8], pos: usize) -> io::Result<u8> {
+    message.get(pos).copied().ok_or_else(|| invalid("truncated response"))
+}
+
+#[cfg(feature = "dot")]
+fn u16_at(message: &[u8], pos: usize) -> io::Result<u16> {
+    Ok(u16::from_be_bytes([byte_at(message, pos)?, byte_at(message, pos + 1)?]))
+}
+
+#[cfg(feature = "dot")]
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
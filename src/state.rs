@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::api::{IpAddr, Service, Token};
+use crate::cache::{CachedRecord, RecordCache};
+use crate::error;
+use crate::history::{History, HistoryEntry};
+use crate::lock;
+use crate::sync::DesiredRecord;
+
+/// The state file format version written by this crate
+pub const STATE_VERSION: u32 = 1;
+
+/// Everything known locally about one record
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StateRecord {
+    pub name: String,
+    /// The last target the API confirmed
+    #[serde(default)]
+    pub target: Option<IpAddr>,
+    #[serde(default)]
+    pub confirmed_at: Option<SystemTime>,
+    #[serde(default)]
+    pub token: Option<Token>,
+    /// Free-form labels such as `env=prod`, for selecting records with `State::select`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>
+}
+
+/// A versioned snapshot of local records, their tokens and history, for backups and machine moves
+///
+/// Tokens are stored in plain text when present, so state files should be treated as secrets.
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub version: u32,
+    #[serde(default)]
+    pub records: Vec<StateRecord>,
+    #[serde(default)]
+    pub history: Vec<HistoryEntry>
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            version: STATE_VERSION,
+            records: Vec::new(),
+            history: Vec::new()
+        }
+    }
+}
+
+impl State {
+    /// Creates an empty state
+    pub fn new() -> Self {
+        State::default()
+    }
+
+    /// Captures the record cache and history attached to a service
+    ///
+    /// Services do not keep tokens, so they need to be added with `set_token`.
+    pub fn capture(service: &Service) -> io::Result<Self> {
+        let mut state = State::new();
+
+        if let Some(cache) = service.cache() {
+            state.records = cache.iter()
+                .map(|(name, record)| StateRecord {
+                    name: name.to_owned(),
+                    target: Some(record.target),
+                    confirmed_at: Some(record.confirmed_at),
+                    token: None,
+                    labels: BTreeMap::new()
+                })
+                .collect();
+        }
+        if let Some(history) = service.history() {
+            state.history = history.entries()?;
+        }
+
+        Ok(state)
+    }
+
+    /// Returns the record with the given name, if the state holds it
+    ///
+    /// A subdomain with both an A and an AAAA record has one record for each; this returns the first.
+    pub fn record(&self, name: &str) -> Option<&StateRecord> {
+        self.records.iter().find(|record| record.name == name)
+    }
+
+    /// Stores the token of a subdomain on each of its records, adding a record if the state does not
+    /// hold the subdomain yet
+    pub fn set_token(&mut self, name: &str, token: impl Into<Token>) {
+        let token = token.into();
+        for record in self.records_named(name) {
+            record.token = Some(token.clone());
+        }
+    }
+
+    /// Sets a label on each record of a subdomain, adding a record if the state does not hold the
+    /// subdomain yet
+    pub fn set_label(&mut self, name: &str, key: &str, value: &str) {
+        for record in self.records_named(name) {
+            record.labels.insert(key.to_owned(), value.to_owned());
+        }
+    }
+
+    // Returns the records of a subdomain, adding an empty one first if there are none
+    fn records_named<'a>(&'a mut self, name: &'a str) -> impl Iterator<Item = &'a mut StateRecord> {
+        if !self.records.iter().any(|record| record.name == name) {
+            self.records.push(StateRecord { name: name.to_owned(), target: None, confirmed_at: None, token: None, labels: BTreeMap::new() });
+        }
+        self.records.iter_mut().filter(move |record| record.name == name)
+    }
+
+    /// Returns a state holding only the records labelled `key=value`, along with their history
+    ///
+    /// The result works like any other state, so `desired` on it gives the records to `sync`, and its
+    /// records can be passed to the bulk operations.
+    pub fn select(&self, key: &str, value: &str) -> State {
+        let records: Vec<StateRecord> = self.records.iter()
+            .filter(|record| record.labels.get(key).is_some_and(|label| label == value))
+            .cloned()
+            .collect();
+        let history = self.history.iter()
+            .filter(|entry| records.iter().any(|record| record.name == entry.subdomain))
+            .cloned()
+            .collect();
+
+        State { version: self.version, records, history }
+    }
+
+    /// Writes the confirmed targets into a record cache and appends the history to a local history
+    pub fn restore(&self, cache: &mut RecordCache, history: Option<&History>) -> io::Result<()> {
+        for record in &self.records {
+            if let Some(target) = record.target {
+                let confirmed_at = record.confirmed_at.unwrap_or_else(SystemTime::now);
+                cache.insert_record(&record.name, CachedRecord { target, confirmed_at })?;
+            }
+        }
+        if let Some(history) = history {
+            for entry in &self.history {
+                history.append(entry)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the records that have a known target as desired records for `sync`
+    pub fn desired(&self) -> Vec<DesiredRecord> {
+        self.records.iter()
+            .filter_map(|record| {
+                let desired = DesiredRecord::new(&record.name, record.target?);
+                Some(match &record.token {
+                    Some(token) => desired.with_token(token.clone()),
+                    None => desired
+                })
+            })
+            .collect()
+    }
+}
+
+/// Writes a state file as JSON
+///
+/// # Errors
+///
+/// This function may return one of the following error variants:
+/// - `DnsApiError::Json(serde_json::Error)`
+/// - `DnsApiError::Io(std::io::Error)`
+pub fn save_state<P: AsRef<Path>>(path: P, state: &State) -> Result<(), error::DnsApiError> {
+    let contents = serde_json::to_string_pretty(state)?;
+    lock::update(path.as_ref(), |_| (contents, ()))?;
+
+    Ok(())
+}
+
+/// Reads a state file written by `save_state`
+///
+/// # Errors
+///
+/// This function may return one of the following error variants:
+/// - `DnsApiError::Json(serde_json::Error)`
+/// - `DnsApiError::Io(std::io::Error)`
+/// - `DnsApiError::UnsupportedStateVersion(u32)` if the file was written by a newer version of this crate
+pub fn load_state<P: AsRef<Path>>(path: P) -> Result<State, error::DnsApiError> {
+    let contents = lock::read(path.as_ref())?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "state file does not exist"))?;
+    let state: State = serde_json::from_str(&contents)?;
+
+    if state.version > STATE_VERSION {
+        return Err(error::DnsApiError::UnsupportedStateVersion(state.version));
+    }
+
+    Ok(state)
+}
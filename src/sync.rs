@@ -0,0 +1,160 @@
+use std::fmt;
+
+use crate::api::{IpAddr, RegisterOutcome, Service, Token, UpdateOutcome};
+use crate::bulk::Progress;
+use crate::error;
+
+/// A record as it should exist once synced
+#[derive(serde_derive::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DesiredRecord {
+    pub name: String,
+    pub target: IpAddr,
+    /// The token of the record if it has already been registered, `None` if it still needs registering
+    #[serde(default)]
+    pub token: Option<Token>
+}
+
+impl DesiredRecord {
+    /// Describes a record that does not exist yet and should be registered with `target`
+    pub fn new(name: &str, target: impl Into<IpAddr>) -> Self {
+        DesiredRecord {
+            name: name.to_owned(),
+            target: target.into(),
+            token: None
+        }
+    }
+
+    /// Marks the record as already registered under `token`, so it is updated instead of registered
+    pub fn with_token(mut self, token: impl Into<Token>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+}
+
+/// The change a `Plan` would make to a single record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// The record has no token yet and would be registered
+    Register,
+    /// The record has drifted and would be updated; `live` holds what DNS currently serves, which is
+    /// empty if the name could not be resolved
+    Update { live: Vec<IpAddr> },
+    /// DNS already serves the desired target
+    Unchanged
+}
+
+/// A desired record together with the change needed to converge it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedRecord {
+    pub record: DesiredRecord,
+    pub change: Change
+}
+
+/// The changes needed to converge live records towards the desired ones, computed by `plan`
+///
+/// Its `Display` output lists one record per line, prefixed with `+` for registrations, `~` for
+/// updates and a space for unchanged records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    pub records: Vec<PlannedRecord>
+}
+
+/// What `apply` did to converge a single record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Registered(RegisterOutcome),
+    Updated(UpdateOutcome),
+    Unchanged
+}
+
+impl Plan {
+    /// Returns `true` if applying the plan would register or update anything
+    pub fn has_changes(&self) -> bool {
+        self.records.iter().any(|planned| planned.change != Change::Unchanged)
+    }
+
+    /// Performs the planned changes and returns what happened to each record, in plan order
+    ///
+    /// The plan is applied as computed; records that changed since `plan` was called are not rechecked.
+    /// Planned updates are always sent, even if the service's `RecordCache` already holds the target,
+    /// since DNS showed that the server no longer does.
+    pub fn apply(&self, service: &Service) -> Vec<(String, Result<Action, error::DnsApiError>)> {
+        self.apply_with_progress(service, |_| {})
+    }
+
+    /// Like `apply`, but calls `progress` after each record
+    pub fn apply_with_progress(&self, service: &Service, mut progress: impl FnMut(Progress<'_>)) -> Vec<(String, Result<Action, error::DnsApiError>)> {
+        self.records.iter()
+            .enumerate()
+            .map(|(i, planned)| {
+                let result = execute(service, planned);
+                progress(Progress { done: i + 1, total: self.records.len(), subdomain: &planned.record.name, error: result.as_ref().err() });
+                (planned.record.name.clone(), result)
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for planned in &self.records {
+            let record = &planned.record;
+            match &planned.change {
+                Change::Register => writeln!(f, "+ {} -> {}", record.name, record.target)?,
+                Change::Update { live } if live.is_empty() => writeln!(f, "~ {}: unresolved -> {}", record.name, record.target)?,
+                Change::Update { live } => {
+                    let live: Vec<String> = live.iter().map(|ip| ip.to_string()).collect();
+                    writeln!(f, "~ {}: {} -> {}", record.name, live.join(", "), record.target)?
+                },
+                Change::Unchanged => writeln!(f, "  {} = {}", record.name, record.target)?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Compares the desired records against what DNS currently serves, without changing anything
+///
+/// Records without a token are planned for registration. Records with a token are planned for an update
+/// if DNS does not serve their target or the name cannot be resolved. The API has no delete operation,
+/// so records that are not in `desired` never appear in the plan.
+pub fn plan(service: &Service, desired: &[DesiredRecord]) -> Plan {
+    let records = desired.iter()
+        .map(|record| {
+            let change = match &record.token {
+                None => Change::Register,
+                Some(_) => match service.lookup(&record.name) {
+                    Ok(live) if live.contains(&record.target) => Change::Unchanged,
+                    Ok(live) => Change::Update { live },
+                    Err(_) => Change::Update { live: Vec::new() }
+                }
+            };
+
+            PlannedRecord { record: record.clone(), change }
+        })
+        .collect();
+
+    Plan { records }
+}
+
+/// Converges the live records towards the desired ones and returns what happened to each, in input order
+///
+/// This is `plan` followed immediately by `Plan::apply`. Newly registered tokens are part of the returned
+/// `Action`s and should be stored, as the API cannot return them again.
+///
+/// Because plans compare against DNS rather than local state, calling this periodically also heals
+/// records that drifted or disappeared on the server while the local address stayed the same.
+pub fn apply(service: &Service, desired: &[DesiredRecord]) -> Vec<(String, Result<Action, error::DnsApiError>)> {
+    plan(service, desired).apply(service)
+}
+
+fn execute(service: &Service, planned: &PlannedRecord) -> Result<Action, error::DnsApiError> {
+    let record = &planned.record;
+
+    match (&planned.change, &record.token) {
+        (Change::Unchanged, _) => Ok(Action::Unchanged),
+        (Change::Update { .. }, Some(token)) => service.update_forced(&record.name, record.target, token).map(Action::Updated),
+        _ => service.register(&record.name, record.target).map(Action::Registered)
+    }
+}
@@ -0,0 +1,96 @@
+use crate::api::IpAddr;
+use crate::error;
+
+// DNS limits a single label to 63 octets
+const MAX_LABEL_LEN: usize = 63;
+
+/// Checks that a subdomain can be sent to the API, returning `DnsApiError::InvalidSubdomain` if not
+///
+/// A subdomain is one or more dot-separated labels of ASCII letters, digits, hyphens and underscores,
+/// without the parent zone, such as `.anondns.net`. The first label may instead be a lone `*` to name a
+/// wildcard record, such as `*.myname`, which covers every name below `myname` in the zone.
+pub fn subdomain(name: &str) -> Result<(), error::DnsApiError> {
+    let invalid = |reason: &str| Err(error::DnsApiError::InvalidSubdomain(format!("{:?} {}", name, reason)));
+    let labels: Vec<&str> = name.split('.').collect();
+
+    for (i, label) in labels.iter().enumerate() {
+        if label.is_empty() {
+            return invalid("contains an empty label");
+        }
+        if label.len() > MAX_LABEL_LEN {
+            return invalid("contains a label longer than 63 characters");
+        }
+        if *label == "*" {
+            if i != 0 || labels.len() == 1 {
+                return invalid("may only use * as the first label of a longer name");
+            }
+            continue;
+        }
+        if !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return invalid("may only contain letters, digits, hyphens and underscores");
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return invalid("has a label starting or ending with a hyphen");
+        }
+    }
+
+    Ok(())
+}
+
+/// Names commonly reserved by DNS providers, for use with `ServiceBuilder::reserved_names`
+pub const COMMON_RESERVED_NAMES: &[&str] = &["www", "api", "mail", "smtp", "imap", "pop", "ftp", "ns", "ns1", "ns2", "admin", "root"];
+
+/// Checks that a subdomain is not on a blocklist, returning `DnsApiError::InvalidSubdomain` if it is
+///
+/// The registered name is the last label, so `*.www` and `blog.www` are rejected along with `www`.
+/// Names are compared case-insensitively.
+pub fn not_reserved<S: AsRef<str>>(name: &str, reserved: &[S]) -> Result<(), error::DnsApiError> {
+    let base = name.rsplit('.').next().unwrap_or(name);
+
+    if reserved.iter().any(|reserved| reserved.as_ref().eq_ignore_ascii_case(base)) {
+        return Err(error::DnsApiError::InvalidSubdomain(format!("{:?} is a reserved name", base)));
+    }
+
+    Ok(())
+}
+
+/// Checks that an address can sensibly be the target of a record, returning `DnsApiError::InvalidTarget` if not
+///
+/// Unspecified, broadcast and multicast addresses are always rejected. Loopback, link-local and private
+/// addresses, including carrier-grade NAT and IPv6 unique local addresses, are only accepted if
+/// `allow_private` is `true`, as they are unreachable from the internet but useful for split-horizon setups.
+pub fn target(target: IpAddr, allow_private: bool) -> Result<(), error::DnsApiError> {
+    let invalid = |reason: &str| Err(error::DnsApiError::InvalidTarget(format!("{} {}", target, reason)));
+
+    if target.is_unspecified() {
+        return invalid("is the unspecified address");
+    }
+    if target.is_multicast() || target == IpAddr::from([255, 255, 255, 255]) {
+        return invalid("is a broadcast or multicast address");
+    }
+    if !allow_private && is_private(target) {
+        return invalid("is a loopback, link-local or private address");
+    }
+
+    Ok(())
+}
+
+fn is_private(target: IpAddr) -> bool {
+    match target {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback() || ip.is_link_local() || ip.is_private() || (a == 100 && (64..128).contains(&b))
+        },
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback() || (first & 0xffc0) == 0xfe80 || (first & 0xfe00) == 0xfc00
+                || ip.to_ipv4_mapped().is_some_and(|ip| is_private(ip.into()))
+        }
+    }
+}
+
+/// Returns `true` if the subdomain names a wildcard record
+pub fn is_wildcard(name: &str) -> bool {
+    name.starts_with("*.")
+}
+
@@ -0,0 +1,66 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::api::{IpAddr, Service};
+use crate::error;
+
+/// A blocking iterator over the changes of a subdomain's addresses, created by `Service::watch`
+///
+/// The first item is the addresses the subdomain resolves to when the iterator is first polled. After
+/// that, each call to `next` resolves the subdomain every interval until its addresses differ from the
+/// last ones yielded, and yields the new ones. Failed lookups are yielded as errors and do not count as
+/// a change, so the watch carries on after them. The iterator never ends.
+///
+/// Addresses come from `Service::lookup`, so changes show up once DNS caches serve them.
+///
+/// # Examples
+///
+/// ```no_run
+/// let service = anondns_api::api::Service::new();
+///
+/// for addresses in service.watch("example_subdomain", std::time::Duration::from_secs(60)).flatten() {
+///     println!("example_subdomain now points to {:?}", addresses);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Watch<'a> {
+    service: &'a Service,
+    subdomain: String,
+    interval: Duration,
+    last: Option<Vec<IpAddr>>,
+    next_poll: Option<Instant>
+}
+
+impl<'a> Watch<'a> {
+    pub(crate) fn new(service: &'a Service, subdomain: &str, interval: Duration) -> Self {
+        Watch {
+            service,
+            subdomain: subdomain.to_owned(),
+            interval,
+            last: None,
+            next_poll: None
+        }
+    }
+}
+
+impl Iterator for Watch<'_> {
+    type Item = Result<Vec<IpAddr>, error::DnsApiError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(at) = self.next_poll {
+                thread::sleep(at.saturating_duration_since(Instant::now()));
+            }
+            self.next_poll = Some(Instant::now() + self.interval);
+
+            match self.service.lookup(&self.subdomain) {
+                Ok(addresses) if self.last.as_ref() == Some(&addresses) => continue,
+                Ok(addresses) => {
+                    self.last = Some(addresses.clone());
+                    return Some(Ok(addresses));
+                },
+                Err(err) => return Some(Err(err))
+            }
+        }
+    }
+}